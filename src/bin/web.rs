@@ -1,25 +1,72 @@
 use askama::Template;
 use axum::{
     Router, ServiceExt,
-    body::Body,
-    extract::{OriginalUri, Query, Request, State},
-    http::{Response, Uri, header},
-    response::{Html, IntoResponse},
+    body::{Body, Bytes},
+    extract::{ConnectInfo, MatchedPath, OriginalUri, Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, Response, Uri, header},
+    middleware::{Next, from_fn, from_fn_with_state, map_response},
+    response::{Html, IntoResponse, Json, Redirect},
     routing::get,
 };
+use axum_server::{Handle, tls_rustls::RustlsConfig};
 use clap::Parser;
-use fjall::{Config, PartitionCreateOptions, PartitionHandle};
-use jiff::{Timestamp, tz::TimeZone};
+use fjall::{Config, Keyspace, PartitionCreateOptions, PartitionHandle, PersistMode};
+use futures_util::stream;
+use image::{
+    ImageDecoder, ImageFormat, ImageReader, ImageResult, codecs::webp::WebPDecoder,
+    imageops::FilterType,
+};
+use ipnet::IpNet;
+use jiff::{SignedDuration, Timestamp, Unit, civil::Date, tz::TimeZone};
+use lru::LruCache;
+use prometheus::{
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec,
+};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::{net::SocketAddr, path::PathBuf, sync::LazyLock};
-use tokio::net::TcpListener;
+use serde_json::{Value, json};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    ops::Bound,
+    path::{self, PathBuf},
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{Notify, Semaphore},
+};
+#[cfg(unix)]
+use tokio::{
+    net::UnixListener,
+    signal::unix::{SignalKind, signal},
+};
 use tower::Layer;
-use tower_http::{normalize_path::NormalizePathLayer, services::ServeDir};
+use tower_http::{
+    normalize_path::{NormalizePath, NormalizePathLayer},
+    services::ServeDir,
+};
 use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use whynot::{get_filename_from_url, kv_sep_partition_option};
+use whynot::{
+    Item, TagCount, author_slug,
+    crawl::{self, CrawlArgs, Run},
+    episode_date, fnv1a, get_filename_from_url, is_safe_filename, kv_sep_partition_option,
+    lock_data_dir, meta_partition, mp4_streams, promo_video, run_partition, sanitize_html,
+    shorthand_dir, summary_partition, tag_key, terminated, vtt_subtitles, write_seq,
+};
 
 /// WHYNOT backup website
 #[derive(Parser, Debug)]
@@ -31,10 +78,373 @@ struct Args {
     /// data folder, containing imgs/ and whynot.db/
     #[arg(short = 'd', long, default_value = "whynot_data")]
     data: String,
+
+    /// public url of this mirror (e.g., https://whynot.example), for share
+    /// previews, sitemaps and feeds; made of --addr if not set
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// serve the mirror under this path (e.g., /whynot) instead of at the root,
+    /// behind a reverse proxy; --base-url, if set, should end with it
+    #[arg(long, default_value = "", value_parser = parse_base_path)]
+    base_path: String,
+
+    /// memory for rendered pages (MB), 0 to not cache them
+    #[arg(long, default_value_t = 64)]
+    page_cache: usize,
+
+    /// /readyz reports stale when the newest story is older than this (hours),
+    /// 0 to never
+    #[arg(long, default_value_t = 72)]
+    stale_after: u64,
+
+    /// listen on this Unix socket instead of --addr, e.g. behind a reverse proxy
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "tls_cert")]
+    unix_socket: Option<PathBuf>,
+
+    /// serve HTTPS with this PEM certificate chain, reloaded with the key on SIGHUP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// also crawl, this long after each crawl ends (e.g., 30m, 2h)
+    #[arg(long)]
+    crawl_interval: Option<SignedDuration>,
+
+    /// most random time added to each --crawl-interval
+    #[arg(long, default_value = "5m")]
+    crawl_jitter: SignedDuration,
+
+    /// TOML file with branding, page size, cache, CSP and rate limit settings, see
+    /// config.example.toml
+    #[arg(short, long, value_parser = load_config)]
+    config: Option<SiteConfig>,
+
+    #[command(flatten, next_help_heading = "Crawl options")]
+    crawl: CrawlArgs,
 }
 
 static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
 
+/// `--base-path` without the trailing slash, empty at the root.
+fn parse_base_path(s: &str) -> Result<String, String> {
+    let path = s.trim_end_matches('/');
+    let safe = |c: char| c.is_ascii_alphanumeric() || "/-._~".contains(c);
+    if !path.is_empty() && (!path.starts_with('/') || !path.chars().all(safe)) {
+        return Err("expected a path like /whynot".to_owned());
+    }
+    Ok(path.to_owned())
+}
+
+/// `--base-path`, for templates.
+fn base_path() -> &'static str {
+    &ARGS.base_path
+}
+
+/// Root-relative `url` under `--base-path`, other urls as they are.
+fn url(url: &str) -> String {
+    if url.starts_with('/') && !url.starts_with("//") {
+        format!("{}{url}", base_path())
+    } else {
+        url.to_owned()
+    }
+}
+
+/// Settings from `--config`, anything left out keeps its default.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct SiteConfig {
+    /// stories per listing page
+    page_size: usize,
+    /// where the mirrored stories come from, for "Source" links
+    source_url: String,
+    /// links in the top bar
+    nav: Vec<Link>,
+    /// links at the end of the front page footer
+    footer: Vec<Link>,
+    /// Content-Security-Policy of the mirror's own pages
+    csp: String,
+    /// Content-Security-Policy of mirrored Shorthand pages
+    shorthand_csp: String,
+    /// reverse proxies whose `X-Forwarded-For` is believed, Unix socket peers
+    /// always are
+    trusted_proxies: Vec<IpNet>,
+    cache: CacheConfig,
+    rate_limit: RateLimitConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct Link {
+    title: String,
+    href: String,
+}
+
+/// `Cache-Control` lifetimes in seconds.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct CacheConfig {
+    /// listing pages, short as new stories keep coming in
+    list_max_age: u32,
+    /// article pages, revalidated with their ETag afterwards
+    article_max_age: u32,
+    /// style sheet, favicon and resized images, in browsers
+    asset_max_age: u32,
+    /// the same in shared caches
+    asset_s_maxage: u32,
+    /// local images and media, which never change under a given name
+    media_max_age: u32,
+}
+
+/// Token buckets per client address, IPv6 clients by /64.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitConfig {
+    /// front page, sections, tags, authors, archive and podcast listings
+    lists: Limit,
+    /// articles, episodes and everything else not listed
+    articles: Limit,
+    /// /imgs, /img, /media and the files of mirrored Shorthand stories
+    images: Limit,
+    /// crawlers that are never limited
+    bots: Vec<Bot>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+struct Limit {
+    /// sustained requests per minute, 0 for no limit
+    per_minute: u32,
+    /// requests allowed in a row before the rate applies
+    burst: u32,
+}
+
+/// A crawler known by its User-Agent, and by its address if `networks` is set,
+/// as a User-Agent alone is easy to fake.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct Bot {
+    user_agent: String,
+    #[serde(default)]
+    networks: Vec<IpNet>,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        let link = |title: &str, href: &str| Link {
+            title: title.to_owned(),
+            href: href.to_owned(),
+        };
+        SiteConfig {
+            page_size: 20,
+            source_url: "https://wainao.me".to_owned(),
+            nav: vec![
+                link("歪脑读", "/wainao-reads"),
+                link("歪脑听", "https://shows.acast.com/here-there"),
+                link("歪脑看", "/wainao-watches"),
+                link("English", "/english"),
+                link("RFA", "https://whynotrfa.org"),
+            ],
+            footer: vec![link("GitHub", "https://github.com/rfa-dev/whynot")],
+            csp: "default-src 'none';img-src 'self';media-src 'self';style-src 'self';".to_owned(),
+            shorthand_csp:
+                "sandbox allow-scripts; default-src 'self' 'unsafe-inline' data:; frame-src 'none'"
+                    .to_owned(),
+            trusted_proxies: ["127.0.0.0/8", "::1/128"]
+                .iter()
+                .map(|n| n.parse().unwrap())
+                .collect(),
+            cache: CacheConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let net = |s: &str| s.parse().unwrap();
+        let bot = |user_agent: &str, networks: &[&str]| Bot {
+            user_agent: user_agent.to_owned(),
+            networks: networks.iter().map(|n| net(n)).collect(),
+        };
+        RateLimitConfig {
+            lists: Limit {
+                per_minute: 60,
+                burst: 30,
+            },
+            articles: Limit {
+                per_minute: 120,
+                burst: 60,
+            },
+            images: Limit {
+                per_minute: 1200,
+                burst: 300,
+            },
+            bots: vec![
+                bot("Googlebot", &["66.249.64.0/19"]),
+                bot(
+                    "bingbot",
+                    &["157.55.39.0/24", "207.46.13.0/24", "40.77.167.0/24"],
+                ),
+            ],
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            list_max_age: 300,
+            article_max_age: 3600,
+            asset_max_age: 1209600,
+            asset_s_maxage: 86400,
+            media_max_age: 31536000,
+        }
+    }
+}
+
+impl SiteConfig {
+    fn validate(&mut self) -> Result<(), String> {
+        if !(1..=500).contains(&self.page_size) {
+            return Err("page_size must be between 1 and 500".to_owned());
+        }
+        self.source_url = self.source_url.trim_end_matches('/').to_owned();
+        if !is_http_url(&self.source_url) {
+            return Err("source_url must be an http(s) url".to_owned());
+        }
+        for link in self.nav.iter().chain(&self.footer) {
+            if link.title.trim().is_empty() {
+                return Err(format!("link to {:?} has no title", link.href));
+            }
+            if !link.href.starts_with('/') && !is_http_url(&link.href) {
+                return Err(format!(
+                    "link {:?} must be a path like /podcast or an http(s) url",
+                    link.title
+                ));
+            }
+        }
+        let limits = &self.rate_limit;
+        for (name, limit) in [
+            ("lists", limits.lists),
+            ("articles", limits.articles),
+            ("images", limits.images),
+        ] {
+            if limit.per_minute > 0 && limit.burst == 0 {
+                return Err(format!("rate_limit.{name}.burst must be at least 1"));
+            }
+        }
+        if limits.bots.iter().any(|b| b.user_agent.is_empty()) {
+            return Err("rate_limit.bots need a user_agent".to_owned());
+        }
+        for (name, csp) in [("csp", &self.csp), ("shorthand_csp", &self.shorthand_csp)] {
+            if HeaderValue::from_str(csp).is_err() {
+                return Err(format!("{name} is not a valid header value"));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Host of `source_url`, for link texts.
+    fn source_host(&self) -> &str {
+        self.source_url
+            .split_once("://")
+            .map_or(&self.source_url, |(_, host)| host)
+    }
+}
+
+fn is_http_url(s: &str) -> bool {
+    ["http://", "https://"]
+        .iter()
+        .any(|scheme| s.strip_prefix(scheme).is_some_and(|rest| !rest.is_empty()))
+}
+
+fn load_config(path: &str) -> Result<SiteConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut config: SiteConfig = toml::from_str(&text).map_err(|e| e.to_string())?;
+    config.validate()?;
+    Ok(config)
+}
+
+static DEFAULT_CONFIG: LazyLock<SiteConfig> = LazyLock::new(SiteConfig::default);
+
+/// `--config`, or the defaults.
+fn config() -> &'static SiteConfig {
+    ARGS.config.as_ref().unwrap_or(&DEFAULT_CONFIG)
+}
+
+/// Fingerprint of the settings and base url, part of the ETag of pages that
+/// render them.
+static CONFIG_HASH: LazyLock<u64> =
+    LazyLock::new(|| fnv1a(format!("{:?}{}", config(), base_url()).as_bytes()));
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "whynot_http_requests_total",
+        "HTTP requests by route and status",
+        &["route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "whynot_http_request_duration_seconds",
+        "HTTP request latency by route",
+        &["route"]
+    )
+    .unwrap()
+});
+
+static NOT_FOUND: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("whynot_not_found_total", "Requests answered with a 404").unwrap()
+});
+
+static RENDER_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "whynot_render_errors_total",
+        "Templates that failed to render"
+    )
+    .unwrap()
+});
+
+static PARTITION_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "whynot_partition_disk_bytes",
+        "Disk space used by each fjall partition",
+        &["partition"]
+    )
+    .unwrap()
+});
+
+static PARTITION_KEYS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "whynot_partition_keys",
+        "Approximate number of keys in each fjall partition",
+        &["partition"]
+    )
+    .unwrap()
+});
+
+static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "whynot_rate_limited_total",
+        "Requests answered with a 429, by rate limit",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+static STORIES: LazyLock<IntGauge> =
+    LazyLock::new(|| register_int_gauge!("whynot_stories", "Stories in the archive").unwrap());
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -45,6 +455,15 @@ async fn main() {
     let folder = PathBuf::from(&ARGS.data);
     let db_folder = folder.join("whynot.db");
 
+    // the database is for one process only, keep spider out while serving
+    let _lock = match lock_data_dir(&folder, "web") {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Can't serve {}: {e}", folder.display());
+            std::process::exit(1);
+        }
+    };
+
     let keyspace = Config::new(db_folder).open().unwrap();
     let db = keyspace
         .open_partition("whynot", kv_sep_partition_option())
@@ -55,69 +474,232 @@ async fn main() {
     let tags = keyspace
         .open_partition("tags", PartitionCreateOptions::default())
         .unwrap();
-    let app_state = AppState { db, index, tags };
+    let tag_counts = keyspace
+        .open_partition("tag_counts", PartitionCreateOptions::default())
+        .unwrap();
+    let authors = keyspace
+        .open_partition("authors", PartitionCreateOptions::default())
+        .unwrap();
+    let podcast = keyspace
+        .open_partition("podcast", PartitionCreateOptions::default())
+        .unwrap();
+    let misses = keyspace
+        .open_partition("misses", PartitionCreateOptions::default())
+        .unwrap();
+    let summaries = summary_partition(&keyspace).unwrap();
+    let meta = meta_partition(&keyspace).unwrap();
+    let runs = run_partition(&keyspace).unwrap();
+    let app_state = AppState {
+        db,
+        index,
+        tags,
+        tag_counts,
+        authors,
+        podcast,
+        misses,
+        summaries,
+        meta,
+        runs,
+        pages: Arc::new(Mutex::new(PageCache::new())),
+        buckets: Arc::new(Mutex::new(RateLimiter::new(config().rate_limit.clone()))),
+    };
+
+    // scraped as 0 before the first 404 or render error
+    for counter in [&NOT_FOUND, &RENDER_ERRORS] {
+        LazyLock::force(counter);
+    }
+
+    let crawler = ARGS.crawl_interval.map(|interval| {
+        crawl::init(&folder, ARGS.crawl.clone()).unwrap();
+        tokio::spawn(crawl_loop(keyspace.clone(), interval))
+    });
 
     let addr: SocketAddr = ARGS.addr.parse().unwrap();
-    info!("Listening to {addr}");
+    if ARGS.base_url.is_none() {
+        warn!(
+            "No --base-url, share previews, sitemaps and feeds link to {}",
+            base_url()
+        );
+    }
 
     let img_folder = folder.join("imgs");
+    let media_folder = folder.join("media");
+    let shorthand_folder = folder.join("shorthand");
+    let cached = from_fn_with_state(app_state.clone(), page_cache);
+    let limited = from_fn_with_state(app_state.clone(), rate_limit);
     let app = Router::new()
-        .route("/", get(list))
-        .route("/{*id}", get(page))
+        .route("/", get(list).route_layer(cached.clone()))
+        .route("/{*id}", get(page).route_layer(cached))
         .route("/style.css", get(style))
         .route("/favicon.ico", get(favicon))
-        .nest_service("/imgs", ServeDir::new(img_folder))
+        .route("/robots.txt", get(robots))
+        .route("/sitemap.xml", get(sitemap_index))
+        .route("/sitemaps/{name}", get(sitemap))
+        .route("/img/{width}/{name}", get(img))
+        .route("/tags", get(tag_list))
+        .route("/topics", get(topic_list))
+        .route("/authors", get(author_list))
+        .route("/authors/{slug}", get(author_page))
+        .route("/archive", get(archive_years))
+        .route("/archive/{year}", get(archive_year))
+        .route("/archive/{year}/{month}", get(archive_month))
+        .route("/archive/{year}/{month}/{day}", get(archive_day))
+        .route("/podcast", get(podcast_list))
+        .route("/podcast/feed.xml", get(podcast_feed))
+        .route("/podcast/{id}", get(episode))
+        .nest_service(
+            "/imgs",
+            map_response(immutable).layer(ServeDir::new(img_folder)),
+        )
+        .nest_service(
+            "/media",
+            map_response(immutable).layer(ServeDir::new(media_folder)),
+        )
+        .nest_service(
+            "/shorthand",
+            map_response(sandbox).layer(ServeDir::new(shorthand_folder)),
+        )
+        .route("/metrics", get(metrics))
+        .route("/status", get(status))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
         .with_state(app_state)
-        .fallback(handler_404);
+        .fallback(handler_404)
+        .layer(limited)
+        .layer(from_fn(track));
+    let app = if base_path().is_empty() {
+        app
+    } else {
+        Router::new().nest(base_path(), app)
+    };
     let app = NormalizePathLayer::trim_trailing_slash().layer(app);
 
-    let listener = TcpListener::bind(addr).await.unwrap();
+    serve(app, addr).await;
 
-    axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
-        .await
-        .unwrap();
+    // connections are drained, a crawl stops at its next batch
+    crawl::stop();
+    if let Some(crawler) = crawler {
+        CRAWL_STOP.notify_one();
+        info!("Waiting for the crawl to store its batch");
+        if let Err(e) = crawler.await {
+            error!("Crawl task failed: {e}");
+        }
+    }
+    keyspace.persist(PersistMode::SyncAll).unwrap();
+    info!("Stopped");
+}
+
+/// Serves `app` on `--unix-socket`, with TLS or plain on `--addr` until
+/// SIGTERM or ctrl-c.
+async fn serve(app: NormalizePath<Router>, addr: SocketAddr) {
+    #[cfg(unix)]
+    if let Some(path) = &ARGS.unix_socket {
+        // left over from a crash, binding would fail
+        if path.metadata().is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path).unwrap();
+        }
+        let listener = UnixListener::bind(path).unwrap();
+        info!("Listening to {}", path.display());
+        axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(path);
+        return;
+    }
+
+    // peer addresses for rate limits, Unix sockets have none
+    let with_peer = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>;
+    if let (Some(cert), Some(key)) = (&ARGS.tls_cert, &ARGS.tls_key) {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let config = RustlsConfig::from_pem_file(cert, key).await.unwrap();
+        #[cfg(unix)]
+        tokio::spawn(reload_tls(config.clone()));
+        let handle = Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown_signal().await;
+                handle.graceful_shutdown(None);
+            }
+        });
+        info!("Listening to https://{addr}");
+        axum_server::bind_rustls(addr, config)
+            .handle(handle)
+            .serve(with_peer(app))
+            .await
+            .unwrap();
+    } else {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        info!("Listening to {addr}");
+        axum::serve(listener, with_peer(app))
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+    }
 }
 
 async fn page(
     Query(params): Query<SiteParams>,
+    headers: HeaderMap,
     State(state): State<AppState>,
     OriginalUri(original_uri): OriginalUri,
-) -> impl IntoResponse {
-    let original_uri = original_uri.to_string();
-    let key = original_uri.split("?").next().unwrap().trim_matches('/');
-    if let Some(v) = state.db.get(key).unwrap() {
+) -> Result<Response<Body>, AppError> {
+    let path = original_uri.path();
+    let key = path
+        .strip_prefix(base_path())
+        .unwrap_or(path)
+        .trim_matches('/');
+    if let Some(v) = state.db.get(key)? {
         info!("page: {key}");
-        let json: Value = serde_json::from_slice(&v).unwrap();
-        let article: Article = (&json).into();
-        into_response(&article)
-    } else {
-        let page = params.page.unwrap_or_default();
-        info!("page: {key}, page:{page}");
-        let n = page * 20;
-        let len = key.len() + 1;
-        let mut prefix = Vec::with_capacity(len);
-        prefix.extend_from_slice(key.as_bytes());
-        prefix.push(b'|');
+        let json: Value =
+            serde_json::from_slice(&v).map_err(|e| AppError::BadRecord(key.to_owned(), e))?;
+        // the page also shows its neighbours, related stories, local files and
+        // settings, which change with any crawl or config
+        let etag = format!(
+            "\"{}-{:016x}-{}-{:016x}\"",
+            env!("CARGO_PKG_VERSION"),
+            fnv1a(&v),
+            write_seq(&state.meta)?,
+            *CONFIG_HASH
+        );
+        if is_fresh(&headers, &etag) {
+            return Ok(not_modified(&etag));
+        }
+        let modified = ["last_updated_date", "first_publish_date"]
+            .iter()
+            .find_map(|k| json[k].as_str()?.parse::<Timestamp>().ok());
 
-        let mut items = Vec::with_capacity(20);
-        for (idx, i) in state.tags.prefix(prefix).rev().enumerate() {
-            if idx < n {
-                continue;
-            }
-            if idx >= n + 20 {
-                break;
-            }
-            let (k, _) = i.unwrap();
-            let website_key = &k[len + 8..];
-            let v2 = state.db.get(website_key).unwrap().unwrap();
-            let json: Value = serde_json::from_slice(&v2).unwrap();
-            let item: Item = (&json).into();
-            items.push(item);
+        let mut article: Article = (&json).into();
+        article.base_url = base_url();
+        if let Some(date) = json["first_publish_date"].as_str()
+            && date.parse::<Timestamp>().is_ok()
+        {
+            let section = article.item.section.0.trim_matches('/');
+            (article.older, article.newer) = neighbours(&state, section, key, date)?;
         }
+        let paths: Vec<_> = article
+            .topics
+            .iter()
+            .chain(&article.tags)
+            .map(|t| &t.0)
+            .collect();
+        article.related = related(&state, &paths, key)?;
 
+        let mut resp = into_cached_response(&article, config().cache.article_max_age)?;
+        let headers = resp.headers_mut();
+        headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+        if let Some(modified) = modified.and_then(|m| http_date(m).ok()) {
+            headers.insert(header::LAST_MODIFIED, modified);
+        }
+        Ok(resp)
+    } else {
+        let page = params.page.unwrap_or_default();
+        info!("page: {key}, page:{page}");
+        let items = prefix_items(&state, &state.tags, key, page)?;
         if items.is_empty() {
             error!("no items found for tag: {key}");
-            return StatusCode::NOT_FOUND.into_response();
+            return Err(missing_story(&state, key));
         }
 
         let url_path = format!("/{key}");
@@ -126,8 +708,47 @@ async fn page(
             page,
             url_path,
         };
-        into_response(&page_list)
+        into_cached_response(&page_list, config().cache.list_max_age)
+    }
+}
+
+/// Index of the first story on `page` and one past its last, a 404 for pages
+/// beyond `usize`.
+fn page_range(page: usize) -> Result<(usize, usize), AppError> {
+    let size = config().page_size;
+    let end = page
+        .checked_add(1)
+        .and_then(|p| p.checked_mul(size))
+        .ok_or(AppError::NotFound)?;
+    Ok((end - size, end))
+}
+
+/// One page of the stories listed under `key` in a `tag_key` partition.
+fn prefix_items(
+    state: &AppState,
+    partition: &PartitionHandle,
+    key: &str,
+    page: usize,
+) -> Result<Vec<Item>, AppError> {
+    let (n, end) = page_range(page)?;
+    let len = key.len() + 1;
+    let mut prefix = Vec::with_capacity(len);
+    prefix.extend_from_slice(key.as_bytes());
+    prefix.push(b'|');
+
+    let mut items = Vec::with_capacity(end - n);
+    for (idx, i) in partition.prefix(prefix).rev().enumerate() {
+        if idx < n {
+            continue;
+        }
+        if idx >= end {
+            break;
+        }
+        let (k, _) = i?;
+        let website_key = &k[len + 8..];
+        items.extend(load_item(state, website_key));
     }
+    Ok(items)
 }
 
 #[derive(Deserialize)]
@@ -143,7 +764,14 @@ enum ContentType {
     Link(String, String),
     RawHtml(String),
     Quote(String),
-    CustomEmbed(String, String),
+    CustomEmbed(String, String, Option<String>),
+    Video(Video),
+    Gallery(String, Vec<(String, String)>),
+    List(bool, Vec<String>),
+    Table(Vec<String>, Vec<Vec<String>>),
+    Divider,
+    Correction(String, String),
+    Oembed(Oembed),
     #[allow(dead_code)]
     Other,
 }
@@ -152,11 +780,18 @@ enum ContentType {
 #[template(path = "article.html", escape = "none")]
 struct Article {
     site: String,
+    base_url: String,
+    published: Option<String>,
+    modified: Option<String>,
     item: Item,
-    author: Option<String>,
+    authors: Vec<Author>,
+    promo_video: Option<Video>,
     contents: Vec<ContentType>,
     topics: Vec<(String, String)>,
     tags: Vec<(String, String)>,
+    older: Option<Item>,
+    newer: Option<Item>,
+    related: Vec<Item>,
 }
 
 impl From<&Value> for Article {
@@ -165,39 +800,40 @@ impl From<&Value> for Article {
         let site = item
             .website_url
             .trim_start_matches('/')
-            .split_once('/')
-            .unwrap()
-            .0
+            .split('/')
+            .next()
+            .unwrap_or_default()
             .to_owned();
-        let author = json
-            .get("credits")
-            .and_then(|p| p.get("by"))
-            .and_then(|b| b.as_array())
-            .and_then(|a| a.first())
-            .and_then(|t| t.get("name"))
-            .and_then(|n| n.as_str())
-            .map(|s| s.to_owned());
+        let authors = json["credits"]["by"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Author::new)
+            .collect();
 
         let mut contents = vec![];
         let mut has_article = false;
         if let Some(content_elements) = json["content_elements"].as_array() {
             for c in content_elements {
-                match c["type"].as_str().unwrap() {
+                match c["type"].as_str().unwrap_or_default() {
                     "text" => {
-                        let content = c["content"].as_str().unwrap();
+                        let content = c["content"].as_str().unwrap_or_default();
                         if !content.is_empty() {
                             contents.push(ContentType::Text(content.to_owned()))
                         }
                     }
                     "image" => {
-                        let url = c["url"].as_str().unwrap();
+                        let Some(url) = c["url"].as_str() else {
+                            warn!("{} -> image without url: {c}", item.website_url);
+                            continue;
+                        };
                         let img_name = get_filename_from_url(url);
                         let url = format!("/imgs/{img_name}");
                         let caption = c["caption"].as_str().unwrap_or_default();
                         contents.push(ContentType::Image(url, caption.to_owned()))
                     }
                     "header" => {
-                        let content = c["content"].as_str().unwrap();
+                        let content = c["content"].as_str().unwrap_or_default();
                         if !content.is_empty() {
                             contents.push(ContentType::Header(content.to_owned()))
                         }
@@ -205,7 +841,7 @@ impl From<&Value> for Article {
                     "interstitial_link" => {
                         let url = c["url"]
                             .as_str()
-                            .unwrap()
+                            .unwrap_or_default()
                             .replace("https://www.rfa.org", "");
                         let content = if let Some(content) = c["content"].as_str() {
                             content.to_owned()
@@ -218,7 +854,7 @@ impl From<&Value> for Article {
                         if !has_article {
                             let content = c["content"]
                                 .as_str()
-                                .unwrap()
+                                .unwrap_or_default()
                                 .trim_start_matches("<noscript>")
                                 .trim_end_matches("</noscript>")
                                 .trim();
@@ -249,17 +885,82 @@ impl From<&Value> for Article {
                         let mut content = String::new();
                         if let Some(config) = c["embed"]["config"].as_object() {
                             if let Some(u) = config.get("shorthandScript") {
-                                url = u.as_str().unwrap().to_owned();
+                                url = u.as_str().unwrap_or_default().to_owned();
                             } else if let Some(u) = config.get("url") {
-                                url = u.as_str().unwrap().to_owned();
+                                url = u.as_str().unwrap_or_default().to_owned();
                             }
-                            if let Some(c) = c.get("article") {
-                                content = c.as_str().unwrap().to_owned();
+                            if let Some(c) = c.get("article").and_then(|c| c.as_str()) {
+                                content = c.replace(
+                                    "src=\"/imgs/",
+                                    &format!("src=\"{}/imgs/", base_path()),
+                                );
                                 has_article = true;
                             }
                         }
-                        contents.push(ContentType::CustomEmbed(url, content));
+                        let dir = shorthand_dir(&url);
+                        let archive = PathBuf::from(&ARGS.data)
+                            .join("shorthand")
+                            .join(&dir)
+                            .join("index.html")
+                            .exists()
+                            .then(|| format!("/shorthand/{dir}/index.html"));
+                        contents.push(ContentType::CustomEmbed(url, content, archive));
                     }
+                    "video" => contents.push(ContentType::Video(c.into())),
+                    "gallery" => {
+                        let title = c["headlines"]["basic"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_owned();
+                        let imgs: Vec<_> = c["content_elements"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|i| {
+                                let url = i["url"].as_str()?;
+                                let url = format!("/imgs/{}", get_filename_from_url(url));
+                                let caption = i["caption"].as_str().unwrap_or_default();
+                                Some((url, caption.to_owned()))
+                            })
+                            .collect();
+                        if !imgs.is_empty() {
+                            contents.push(ContentType::Gallery(title, imgs));
+                        }
+                    }
+                    "list" => {
+                        let ordered = c["list_type"].as_str() == Some("ordered");
+                        contents.push(ContentType::List(ordered, list_items(c)));
+                    }
+                    "table" => {
+                        let cell = |v: &Value| v["content"].as_str().unwrap_or_default().to_owned();
+                        let header = c["header"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .map(cell)
+                            .collect();
+                        let rows = c["rows"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|r| r.as_array())
+                            .map(|r| r.iter().map(cell).collect())
+                            .collect();
+                        contents.push(ContentType::Table(header, rows));
+                    }
+                    "divider" => contents.push(ContentType::Divider),
+                    "correction" => {
+                        let kind = match c["correction_type"].as_str() {
+                            Some("clarification") => "Clarification",
+                            _ => "Correction",
+                        };
+                        let text = c["text"].as_str().unwrap_or_default();
+                        if !text.is_empty() {
+                            contents
+                                .push(ContentType::Correction(kind.to_owned(), text.to_owned()));
+                        }
+                    }
+                    "oembed_response" => contents.push(ContentType::Oembed(c.into())),
                     _ => {
                         warn!("{} -> unknown content type: {c}", item.website_url)
                     }
@@ -272,8 +973,8 @@ impl From<&Value> for Article {
 
         if let Some(sections) = json["taxonomy"]["sections"].as_array() {
             for section in sections {
-                let path = section["path"].as_str().unwrap().to_owned();
-                let name = section["name"].as_str().unwrap().to_owned();
+                let path = section["path"].as_str().unwrap_or_default().to_owned();
+                let name = section["name"].as_str().unwrap_or_default().to_owned();
                 if path.starts_with("/topics") {
                     topics.push((path, name));
                 } else if path.starts_with("/tags") {
@@ -282,13 +983,239 @@ impl From<&Value> for Article {
             }
         }
 
+        let promo_video = promo_video(json).map(Video::from);
+        let date = |k: &str| {
+            let ts: Timestamp = json[k].as_str()?.parse().ok()?;
+            Some(ts.to_string())
+        };
+
         Self {
             site,
+            base_url: String::new(),
+            published: date("first_publish_date"),
+            modified: date("last_updated_date"),
             item,
-            author,
+            authors,
+            promo_video,
             contents,
             topics,
             tags,
+            older: None,
+            newer: None,
+            related: vec![],
+        }
+    }
+}
+
+impl Article {
+    /// schema.org `NewsArticle`, safe to embed in a `<script>` element.
+    fn json_ld(&self) -> String {
+        let base = &self.base_url;
+        let authors: Vec<_> = self
+            .authors
+            .iter()
+            .map(|a| json!({"@type": "Person", "name": a.name, "url": format!("{base}/authors/{}", urlencoding::encode(&a.slug))}))
+            .collect();
+        let mut ld = json!({
+            "@context": "https://schema.org",
+            "@type": "NewsArticle",
+            "headline": self.item.headlines,
+            "description": self.item.description,
+            "url": format!("{base}{}", self.item.website_url),
+            "author": authors,
+        });
+        if let Some(img) = &self.item.promo_img {
+            ld["image"] = json!([format!("{base}{img}")]);
+        }
+        if let Some(published) = &self.published {
+            ld["datePublished"] = json!(published);
+        }
+        if let Some(modified) = &self.modified {
+            ld["dateModified"] = json!(modified);
+        }
+        ld.to_string()
+            .replace('<', "\\u003c")
+            .replace('>', "\\u003e")
+            .replace('&', "\\u0026")
+    }
+}
+
+/// The stories published right before and after `key` in `section`.
+fn neighbours(
+    state: &AppState,
+    section: &str,
+    key: &str,
+    date: &str,
+) -> Result<(Option<Item>, Option<Item>), AppError> {
+    let prefix = format!("{section}|").into_bytes();
+    let own = tag_key(section, key, date);
+    let website_key = |k: &[u8]| k[prefix.len() + 8..].to_vec();
+
+    let older = match state.tags.range(prefix.clone()..own.clone()).next_back() {
+        Some(kv) => load_item(state, &website_key(&kv?.0)),
+        None => None,
+    };
+    let newer = match state
+        .tags
+        .range((Bound::Excluded(own), Bound::Unbounded))
+        .next()
+    {
+        Some(kv) => {
+            let (k, _) = kv?;
+            if k.starts_with(&prefix) {
+                load_item(state, &website_key(&k))
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+    Ok((older, newer))
+}
+
+/// Most recent stories looked at per tag when ranking related stories.
+const RELATED_SCAN: usize = 50;
+
+/// Stories sharing the most `paths` with `key`, newest first among equals.
+fn related(state: &AppState, paths: &[&String], key: &str) -> Result<Vec<Item>, AppError> {
+    let mut shared: HashMap<Vec<u8>, (usize, Vec<u8>)> = HashMap::new();
+    for path in paths {
+        let mut prefix = path.trim_matches('/').as_bytes().to_vec();
+        prefix.push(b'|');
+        let len = prefix.len();
+        for i in state.tags.prefix(prefix).rev().take(RELATED_SCAN) {
+            let (k, _) = i?;
+            let website_key = &k[len + 8..];
+            if website_key == key.as_bytes() {
+                continue;
+            }
+            let entry = shared
+                .entry(website_key.to_vec())
+                .or_insert_with(|| (0, k[len..len + 8].to_vec()));
+            entry.0 += 1;
+        }
+    }
+
+    let mut ranked: Vec<_> = shared.into_iter().collect();
+    ranked.sort_by(|(_, a), (_, b)| (b.0, &b.1).cmp(&(a.0, &a.1)));
+    Ok(ranked
+        .into_iter()
+        .filter_map(|(k, _)| load_item(state, &k))
+        .take(5)
+        .collect())
+}
+
+/// Rendered `<li>` bodies of an ANS list, with nested lists inlined.
+fn list_items(list: &Value) -> Vec<String> {
+    let mut items = vec![];
+    for i in list["items"].as_array().into_iter().flatten() {
+        match i["type"].as_str() {
+            Some("text") => items.push(i["content"].as_str().unwrap_or_default().to_owned()),
+            Some("list") => {
+                let tag = if i["list_type"].as_str() == Some("ordered") {
+                    "ol"
+                } else {
+                    "ul"
+                };
+                let nested: String = list_items(i)
+                    .iter()
+                    .map(|li| format!("<li>{li}</li>"))
+                    .collect();
+                let nested = format!("<{tag}>{nested}</{tag}>");
+                match items.last_mut() {
+                    Some(last) => last.push_str(&nested),
+                    None => items.push(nested),
+                }
+            }
+            _ => {}
+        }
+    }
+    items
+}
+
+/// Static card for an embedded tweet, video etc., so nothing is loaded from
+/// the provider.
+#[derive(Debug, Serialize)]
+struct Oembed {
+    provider: String,
+    title: String,
+    author: String,
+    url: String,
+    thumbnail: Option<String>,
+}
+
+impl From<&Value> for Oembed {
+    fn from(c: &Value) -> Self {
+        let raw = &c["raw_oembed"];
+        let field = |k: &str| raw[k].as_str().unwrap_or_default().to_owned();
+        let provider = raw["provider_name"]
+            .as_str()
+            .or_else(|| c["subtype"].as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let url = raw["url"]
+            .as_str()
+            .or_else(|| raw["_id"].as_str())
+            .or_else(|| c["referent"]["id"].as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let thumbnail = raw["thumbnail_url"].as_str().and_then(|url| {
+            let name = get_filename_from_url(url);
+            PathBuf::from(&ARGS.data)
+                .join("imgs")
+                .join(&name)
+                .exists()
+                .then(|| format!("/imgs/{name}"))
+        });
+
+        Oembed {
+            provider,
+            title: field("title"),
+            author: field("author_name"),
+            url,
+            thumbnail,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Video {
+    src: Option<String>,
+    poster: Option<String>,
+    subtitles: Option<String>,
+    caption: String,
+}
+
+impl From<&Value> for Video {
+    /// Only links files the spider actually saved in `media/`.
+    fn from(video: &Value) -> Self {
+        let media_folder = PathBuf::from(&ARGS.data).join("media");
+        let local = |url: &str| {
+            let name = get_filename_from_url(url);
+            media_folder
+                .join(&name)
+                .exists()
+                .then(|| format!("/media/{name}"))
+        };
+
+        let src = mp4_streams(video)
+            .into_iter()
+            .find_map(|(url, _)| local(url));
+        let subtitles = vtt_subtitles(video).and_then(local);
+        let poster = video["promo_image"]["url"]
+            .as_str()
+            .map(|url| format!("/imgs/{}", get_filename_from_url(url)));
+        let caption = video["description"]["basic"]
+            .as_str()
+            .or_else(|| video["headlines"]["basic"].as_str())
+            .unwrap_or_default()
+            .to_owned();
+
+        Video {
+            src,
+            poster,
+            subtitles,
+            caption,
         }
     }
 }
@@ -296,26 +1223,20 @@ impl From<&Value> for Article {
 async fn list(
     Query(params): Query<SiteParams>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    let index = state.index;
-    let db = state.db;
-    let mut items = Vec::with_capacity(20);
+) -> Result<Response<Body>, AppError> {
     let page = params.page.unwrap_or(0);
-    let n = page * 20;
-    for (idx, i) in index.iter().rev().enumerate() {
+    let (n, end) = page_range(page)?;
+    let mut items = Vec::with_capacity(end - n);
+    for (idx, i) in state.index.iter().rev().enumerate() {
         if idx < n {
             continue;
         }
-        if idx >= n + 20 {
+        if idx >= end {
             break;
         }
-        let (k, _) = i.unwrap();
+        let (k, _) = i?;
         let db_key = &k[8..];
-        if let Some(v) = db.get(db_key).unwrap() {
-            let json: Value = serde_json::from_slice(&v).unwrap();
-            let item: Item = (&json).into();
-            items.push(item)
-        }
+        items.extend(load_item(&state, db_key));
     }
 
     let url_path = "/".to_owned();
@@ -324,129 +1245,1727 @@ async fn list(
         page,
         url_path,
     };
-    into_response(&page_list)
-}
-
-async fn handler_404(uri: Uri) -> impl IntoResponse {
-    error!("No route for {}", uri);
-    (
-        StatusCode::NOT_FOUND,
-        Html("404 NOT FOUND.<br>Back to <a href='/'>Home</a>"),
-    )
-}
-
-#[derive(Clone)]
-struct AppState {
-    db: PartitionHandle,
-    index: PartitionHandle,
-    tags: PartitionHandle,
+    into_cached_response(&page_list, config().cache.list_max_age)
 }
 
 #[derive(Debug, Serialize)]
-struct Item {
-    headlines: String,
-    display_date: String,
-    description: String,
-    promo_img: Option<String>,
-    caption: Option<String>,
-    website_url: String,
-    section: (String, String),
+struct Author {
+    slug: String,
+    name: String,
+    bio: String,
+    image: Option<String>,
 }
 
-impl From<&Value> for Item {
-    fn from(json: &Value) -> Self {
-        let headlines = json["headlines"]["basic"].as_str().unwrap().to_owned();
-        let display_date = json["publish_date"].as_str().unwrap();
-        let ts: Timestamp = display_date.parse().unwrap();
-        let display_date = ts.to_zoned(TimeZone::UTC).strftime("%Y-%m-%d").to_string();
-
-        let description = json["description"]["basic"].as_str().unwrap().to_owned();
-
-        let promo_img = json
-            .get("promo_items")
-            .and_then(|p| p.get("basic"))
-            .and_then(|b| b.get("url"))
-            .and_then(|img| img.as_str())
-            .map(|s| {
-                let img_name = get_filename_from_url(s);
-                format!("/imgs/{img_name}")
-            });
-
-        let caption = json
-            .get("promo_items")
-            .and_then(|p| p.get("basic"))
-            .and_then(|b| b.get("caption"))
-            .and_then(|c| c.as_str())
-            .map(|s| s.to_owned());
-
-        let mut id = String::new();
-        let mut name = String::new();
-        let mut website_url = String::new();
-        if let Some(obj) = json["websites"].as_object()
-            && let Some((_, value)) = obj.iter().next()
-        {
-            website_url = value["website_url"].as_str().unwrap().to_owned();
-            let section = value.get("website_section").unwrap();
-            id = section
-                .get("_id")
-                .unwrap_or_default()
-                .as_str()
-                .unwrap_or_default()
-                .to_owned();
-            name = section
-                .get("name")
-                .unwrap_or_default()
-                .as_str()
-                .unwrap_or_default()
-                .to_owned();
-        }
-        Item {
-            headlines,
-            display_date,
-            description,
-            promo_img,
-            caption,
-            website_url,
-            section: (id, name),
-        }
+impl Author {
+    fn new(credit: &Value) -> Option<Self> {
+        let slug = author_slug(credit)?;
+        let name = credit["name"].as_str().unwrap_or(&slug).to_owned();
+        let bio = ["description", "bio"]
+            .into_iter()
+            .find_map(|k| {
+                credit[k]
+                    .as_str()
+                    .or_else(|| credit["additional_properties"]["original"][k].as_str())
+                    .filter(|s| !s.is_empty())
+            })
+            .unwrap_or_default()
+            .to_owned();
+        let image = credit["image"]["url"]
+            .as_str()
+            .map(|url| format!("/imgs/{}", get_filename_from_url(url)));
+        Some(Author {
+            slug,
+            name,
+            bio,
+            image,
+        })
     }
 }
 
 #[derive(Template)]
-#[template(path = "list.html")]
-struct PageList {
+#[template(path = "author.html")]
+struct AuthorPage {
+    author: Author,
     items: Vec<Item>,
     page: usize,
     url_path: String,
 }
 
-fn into_response<T: Template>(t: &T) -> Response<Body> {
-    match t.render() {
-        Ok(body) => Html(body).into_response(),
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    }
-}
+async fn author_page(
+    Path(slug): Path<String>,
+    Query(params): Query<SiteParams>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    let page = params.page.unwrap_or_default();
+    let items = prefix_items(&state, &state.authors, &slug, page)?;
 
-async fn style() -> impl IntoResponse {
-    let headers = [
-        (header::CONTENT_TYPE, "text/css"),
-        (
-            header::CACHE_CONTROL,
-            "public, max-age=1209600, s-maxage=86400",
-        ),
-    ];
+    // name, bio and image as credited in the newest story
+    let mut prefix = slug.clone().into_bytes();
+    prefix.push(b'|');
+    let author = match state.authors.prefix(prefix).next_back() {
+        Some(kv) => {
+            let (k, _) = kv?;
+            state
+                .db
+                .get(&k[slug.len() + 1 + 8..])?
+                .and_then(|v| serde_json::from_slice::<Value>(&v).ok())
+                .and_then(|json| {
+                    json["credits"]["by"]
+                        .as_array()?
+                        .iter()
+                        .filter_map(Author::new)
+                        .find(|a| a.slug == slug)
+                })
+        }
+        None => None,
+    };
+    let Some(author) = author else {
+        return Err(AppError::NotFound);
+    };
 
-    (headers, include_str!("../../static/style.css"))
+    let url_path = format!("/authors/{}", urlencoding::encode(&slug));
+    let author_page = AuthorPage {
+        author,
+        items,
+        page,
+        url_path,
+    };
+    into_cached_response(&author_page, config().cache.list_max_age)
 }
 
-async fn favicon() -> impl IntoResponse {
-    let headers = [
-        (header::CONTENT_TYPE, "image/x-icon"),
-        (
-            header::CACHE_CONTROL,
-            "public, max-age=1209600, s-maxage=86400",
-        ),
-    ];
+async fn author_list(
+    Query(params): Query<TagParams>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    tag_dir(&state, "authors", "Authors", params)
+}
+
+#[derive(Deserialize)]
+struct TagParams {
+    sort: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "tags.html")]
+struct TagList {
+    title: &'static str,
+    url_path: &'static str,
+    by_count: bool,
+    tags: Vec<(String, TagCount)>,
+}
+
+async fn tag_list(
+    Query(params): Query<TagParams>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    tag_dir(&state, "tags", "Tags", params)
+}
+
+async fn topic_list(
+    Query(params): Query<TagParams>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    tag_dir(&state, "topics", "Topics", params)
+}
+
+/// Every section under `kind/`, by name or, with `?sort=count`, by story count.
+fn tag_dir(
+    state: &AppState,
+    kind: &'static str,
+    title: &'static str,
+    params: TagParams,
+) -> Result<Response<Body>, AppError> {
+    let mut tags = Vec::new();
+    for i in state.tag_counts.prefix(format!("{kind}/")) {
+        let (k, v) = i?;
+        let path = String::from_utf8_lossy(&k).into_owned();
+        match serde_json::from_slice::<TagCount>(&v) {
+            Ok(tag) => tags.push((path, tag)),
+            Err(e) => warn!("bad tag count {path}: {e}"),
+        }
+    }
+
+    let by_count = params.sort.as_deref() == Some("count");
+    if by_count {
+        tags.sort_by_key(|(_, t)| Reverse(t.count));
+    } else {
+        tags.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+    }
+
+    let tag_list = TagList {
+        title,
+        url_path: match kind {
+            "tags" => "/tags",
+            "topics" => "/topics",
+            _ => "/authors",
+        },
+        by_count,
+        tags,
+    };
+    into_cached_response(&tag_list, config().cache.list_max_age)
+}
+
+#[derive(Debug)]
+struct Month {
+    year: i16,
+    month: i8,
+    total: usize,
+    /// Monday first, `None` outside the month, otherwise (day, stories).
+    weeks: Vec<Vec<Option<(i8, usize)>>>,
+}
+
+impl Month {
+    fn new(year: i16, month: i8, counts: &BTreeMap<Date, usize>) -> Option<Self> {
+        let first = Date::new(year, month, 1).ok()?;
+        let mut cells = vec![None; first.weekday().to_monday_zero_offset() as usize];
+        let mut total = 0;
+        for day in 1..=first.days_in_month() {
+            let count = counts
+                .get(&first.with().day(day).build().ok()?)
+                .copied()
+                .unwrap_or(0);
+            total += count;
+            cells.push(Some((day, count)));
+        }
+        while cells.len() % 7 != 0 {
+            cells.push(None);
+        }
+        let weeks = cells.chunks(7).map(|w| w.to_vec()).collect();
+        Some(Month {
+            year,
+            month,
+            total,
+            weeks,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "archive.html")]
+struct ArchivePage {
+    title: String,
+    prev: Option<String>,
+    next: Option<String>,
+    years: Vec<i16>,
+    months: Vec<Month>,
+    items: Vec<Item>,
+    page: usize,
+    url_path: String,
+}
+
+/// `index` key bound for the start of `date`, UTC.
+fn date_key(date: Date) -> Option<[u8; 8]> {
+    let ts = date.to_zoned(TimeZone::UTC).ok()?.timestamp();
+    Some(ts.as_second().to_be_bytes())
+}
+
+/// Stories per day published in `start..end`.
+fn day_counts(
+    index: &PartitionHandle,
+    start: Date,
+    end: Date,
+) -> Result<BTreeMap<Date, usize>, AppError> {
+    let (Some(from), Some(to)) = (date_key(start), date_key(end)) else {
+        return Err(AppError::NotFound);
+    };
+    let mut counts = BTreeMap::new();
+    for i in index.range(from..to) {
+        let (k, _) = i?;
+        let secs = i64::from_be_bytes(k[..8].try_into().unwrap_or_default());
+        if let Ok(ts) = Timestamp::from_second(secs) {
+            *counts.entry(ts.to_zoned(TimeZone::UTC).date()).or_default() += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// One page of stories published in `start..end`, newest first.
+fn items_between(
+    state: &AppState,
+    start: Date,
+    end: Date,
+    page: usize,
+) -> Result<Vec<Item>, AppError> {
+    let (Some(from), Some(to)) = (date_key(start), date_key(end)) else {
+        return Err(AppError::NotFound);
+    };
+    let (n, end) = page_range(page)?;
+    let mut items = Vec::with_capacity(end - n);
+    for i in state.index.range(from..to).rev().skip(n).take(end - n) {
+        let (k, _) = i?;
+        items.extend(load_item(state, &k[8..]));
+    }
+    Ok(items)
+}
+
+async fn archive_years(State(state): State<AppState>) -> Result<Response<Body>, AppError> {
+    let year = |kv: Option<fjall::KvPair>| {
+        let (k, _) = kv?;
+        let secs = i64::from_be_bytes(k.get(..8)?.try_into().ok()?);
+        let ts = Timestamp::from_second(secs).ok()?;
+        Some(ts.to_zoned(TimeZone::UTC).year())
+    };
+    let first = year(state.index.first_key_value()?);
+    let last = year(state.index.last_key_value()?);
+    let years = match (first, last) {
+        (Some(first), Some(last)) => (first..=last).rev().collect(),
+        _ => vec![],
+    };
+
+    let archive = ArchivePage {
+        title: "Archive".to_owned(),
+        prev: None,
+        next: None,
+        years,
+        months: vec![],
+        items: vec![],
+        page: 0,
+        url_path: "/archive".to_owned(),
+    };
+    into_cached_response(&archive, config().cache.list_max_age)
+}
+
+async fn archive_year(
+    Path(year): Path<i16>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    let start = Date::new(year, 1, 1).map_err(|_| AppError::NotFound)?;
+    let end = Date::new(year + 1, 1, 1).map_err(|_| AppError::NotFound)?;
+    let counts = day_counts(&state.index, start, end)?;
+    let months = (1..=12)
+        .filter_map(|m| Month::new(year, m, &counts))
+        .collect();
+
+    let archive = ArchivePage {
+        title: year.to_string(),
+        prev: Some(format!("/archive/{}", year - 1)),
+        next: Some(format!("/archive/{}", year + 1)),
+        years: vec![],
+        months,
+        items: vec![],
+        page: 0,
+        url_path: format!("/archive/{year}"),
+    };
+    into_cached_response(&archive, config().cache.list_max_age)
+}
+
+async fn archive_month(
+    Path((year, month)): Path<(i16, i8)>,
+    Query(params): Query<SiteParams>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    let start = Date::new(year, month, 1).map_err(|_| AppError::NotFound)?;
+    let end = start
+        .last_of_month()
+        .tomorrow()
+        .map_err(|_| AppError::NotFound)?;
+    let prev = start.yesterday().map_err(|_| AppError::NotFound)?;
+    let page = params.page.unwrap_or_default();
+    let counts = day_counts(&state.index, start, end)?;
+
+    let archive = ArchivePage {
+        title: format!("{year}-{month:02}"),
+        prev: Some(format!("/archive/{}/{:02}", prev.year(), prev.month())),
+        next: Some(format!("/archive/{}/{:02}", end.year(), end.month())),
+        years: vec![],
+        months: Month::new(year, month, &counts).into_iter().collect(),
+        items: items_between(&state, start, end, page)?,
+        page,
+        url_path: format!("/archive/{year}/{month:02}"),
+    };
+    into_cached_response(&archive, config().cache.list_max_age)
+}
+
+async fn archive_day(
+    Path((year, month, day)): Path<(i16, i8, i8)>,
+    Query(params): Query<SiteParams>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    let date = Date::new(year, month, day).map_err(|_| AppError::NotFound)?;
+    let end = date.tomorrow().map_err(|_| AppError::NotFound)?;
+    let prev = date.yesterday().map_err(|_| AppError::NotFound)?;
+    let page = params.page.unwrap_or_default();
+    let day_path = |d: Date| format!("/archive/{}/{:02}/{:02}", d.year(), d.month(), d.day());
+
+    let archive = ArchivePage {
+        title: format!("{year}-{month:02}-{day:02}"),
+        prev: Some(day_path(prev)),
+        next: Some(day_path(end)),
+        years: vec![],
+        months: vec![],
+        items: items_between(&state, date, end, page)?,
+        page,
+        url_path: day_path(date),
+    };
+    into_cached_response(&archive, config().cache.list_max_age)
+}
+
+async fn handler_404(uri: Uri) -> AppError {
+    error!("No route for {}", uri);
+    AppError::NotFound
+}
+
+/// Listing row for a story, `None` if it is missing or unreadable, so one bad
+/// record only drops its own row.
+///
+/// Read from `summaries`, falling back to the whole story for archives not
+/// backfilled yet.
+fn load_item(state: &AppState, key: &[u8]) -> Option<Item> {
+    match state.summaries.get(key) {
+        Ok(Some(v)) => match Item::from_bytes(&v) {
+            Ok(item) => return Some(item),
+            Err(e) => warn!("bad summary {}: {e}", String::from_utf8_lossy(key)),
+        },
+        Ok(None) => {}
+        Err(e) => error!("can't read summary {}: {e}", String::from_utf8_lossy(key)),
+    }
+
+    let v = state.db.get(key);
+    let key = String::from_utf8_lossy(key);
+    match v {
+        Ok(Some(v)) => match serde_json::from_slice::<Value>(&v) {
+            Ok(json) => Some((&json).into()),
+            Err(e) => {
+                error!("bad record {key}: {e}");
+                None
+            }
+        },
+        Ok(None) => {
+            warn!("missing story: {key}");
+            None
+        }
+        Err(e) => {
+            error!("can't read {key}: {e}");
+            None
+        }
+    }
+}
+
+/// Stories in the same section sharing the most slug words with `key`.
+fn suggestions(tags: &PartitionHandle, section: &str, key: &str) -> Vec<Vec<u8>> {
+    let words: HashSet<&str> = key
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .split('-')
+        .filter(|w| w.len() > 1)
+        .collect();
+
+    let prefix = format!("{section}|");
+    let mut candidates: Vec<(usize, Vec<u8>)> = tags
+        .prefix(&prefix)
+        .rev()
+        .take(MAX_SUGGESTION_CANDIDATES)
+        .filter_map(|i| {
+            let (k, _) = i.ok()?;
+            let website_key = k[prefix.len() + 8..].to_vec();
+            let slug = String::from_utf8_lossy(&website_key);
+            let slug = slug.rsplit('/').next().unwrap_or_default();
+            let score = slug.split('-').filter(|w| words.contains(w)).count();
+            Some((score, website_key))
+        })
+        .collect();
+
+    // stable, so equal scores stay newest first
+    candidates.sort_by_key(|(score, _)| Reverse(*score));
+    candidates.into_iter().take(5).map(|(_, k)| k).collect()
+}
+
+/// How many of a section's newest stories are searched for suggestions.
+const MAX_SUGGESTION_CANDIDATES: usize = 500;
+
+/// Upper bound on recorded misses, so crawlers can't grow it forever.
+const MAX_MISSES: usize = 10_000;
+
+/// 404 for a tag or story path. Paths under a section that has stories are
+/// taken as stories we haven't archived: they get a link to the original and
+/// suggestions, and are recorded in `misses` for the spider to fetch.
+fn missing_story(state: &AppState, key: &str) -> AppError {
+    let section = key.split('/').next().unwrap_or_default();
+    if section == key || state.tags.prefix(format!("{section}|")).next().is_none() {
+        return AppError::NotFound;
+    }
+
+    if key.len() <= 256 && state.misses.approximate_len() < MAX_MISSES {
+        let hits = match state.misses.get(key) {
+            Ok(Some(v)) => v.as_ref().try_into().map(u64::from_be_bytes).unwrap_or(0),
+            _ => 0,
+        };
+        if let Err(e) = state.misses.insert(key, (hits + 1).to_be_bytes()) {
+            error!("can't record miss {key}: {e}");
+        }
+    }
+
+    let items = suggestions(&state.tags, section, key)
+        .iter()
+        .filter_map(|k| load_item(state, k))
+        .collect();
+    AppError::MissingStory(key.to_owned(), items)
+}
+
+#[derive(Debug)]
+enum AppError {
+    NotFound,
+    MissingStory(String, Vec<Item>),
+    Db(fjall::Error),
+    BadRecord(String, serde_json::Error),
+    Render(askama::Error),
+}
+
+impl From<fjall::Error> for AppError {
+    fn from(e: fjall::Error) -> Self {
+        AppError::Db(e)
+    }
+}
+
+impl From<askama::Error> for AppError {
+    fn from(e: askama::Error) -> Self {
+        AppError::Render(e)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "404.html")]
+struct NotFoundPage {
+    original: Option<String>,
+    items: Vec<Item>,
+}
+
+#[derive(Template)]
+#[template(path = "500.html")]
+struct ErrorPage;
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response<Body> {
+        let (status, page) = match self {
+            AppError::NotFound => {
+                let page = NotFoundPage {
+                    original: None,
+                    items: vec![],
+                };
+                (StatusCode::NOT_FOUND, page.render())
+            }
+            AppError::MissingStory(key, items) => {
+                let page = NotFoundPage {
+                    original: Some(format!("{}/{key}", config().source_url)),
+                    items,
+                };
+                (StatusCode::NOT_FOUND, page.render())
+            }
+            AppError::Db(e) => {
+                error!("db error: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorPage.render())
+            }
+            AppError::BadRecord(key, e) => {
+                error!("bad record {key}: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorPage.render())
+            }
+            AppError::Render(e) => {
+                RENDER_ERRORS.inc();
+                error!("render error: {e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorPage.render())
+            }
+        };
+        match page {
+            Ok(body) => (status, Html(body)).into_response(),
+            Err(_) => status.into_response(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    db: PartitionHandle,
+    index: PartitionHandle,
+    tags: PartitionHandle,
+    tag_counts: PartitionHandle,
+    authors: PartitionHandle,
+    podcast: PartitionHandle,
+    misses: PartitionHandle,
+    summaries: PartitionHandle,
+    meta: PartitionHandle,
+    runs: PartitionHandle,
+    pages: Arc<Mutex<PageCache>>,
+    buckets: Arc<Mutex<RateLimiter>>,
+}
+
+/// Rendered pages by path and page number, dropped whenever the spider bumps
+/// the write sequence number.
+struct PageCache {
+    seq: u64,
+    bytes: usize,
+    pages: LruCache<String, (HeaderMap, Bytes)>,
+}
+
+impl PageCache {
+    fn new() -> Self {
+        PageCache {
+            seq: 0,
+            bytes: 0,
+            pages: LruCache::unbounded(),
+        }
+    }
+
+    fn sync(&mut self, seq: u64) {
+        if seq != self.seq {
+            self.pages.clear();
+            self.bytes = 0;
+            self.seq = seq;
+        }
+    }
+
+    fn get(&mut self, seq: u64, key: &str) -> Option<(HeaderMap, Bytes)> {
+        self.sync(seq);
+        self.pages.get(key).cloned()
+    }
+
+    fn put(&mut self, seq: u64, key: String, headers: HeaderMap, body: Bytes) {
+        let cap = ARGS.page_cache * 1_000_000;
+        if body.len() > cap {
+            return;
+        }
+        self.sync(seq);
+        self.bytes += body.len();
+        if let Some((_, (_, old))) = self.pages.push(key, (headers, body)) {
+            self.bytes -= old.len();
+        }
+        while self.bytes > cap {
+            match self.pages.pop_lru() {
+                Some((_, (_, old))) => self.bytes -= old.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum LimitClass {
+    Lists,
+    Articles,
+    Images,
+}
+
+impl LimitClass {
+    fn name(self) -> &'static str {
+        match self {
+            LimitClass::Lists => "lists",
+            LimitClass::Articles => "articles",
+            LimitClass::Images => "images",
+        }
+    }
+
+    fn limit(self, limits: &RateLimitConfig) -> Limit {
+        match self {
+            LimitClass::Lists => limits.lists,
+            LimitClass::Articles => limits.articles,
+            LimitClass::Images => limits.images,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Tokens after refilling at `limit` until `now`.
+    fn refill(&self, limit: Limit, now: Instant) -> f64 {
+        let rate = f64::from(limit.per_minute) / 60.0;
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(f64::from(limit.burst))
+    }
+}
+
+/// Token buckets by class and client, full ones are dropped now and then.
+struct RateLimiter {
+    limits: RateLimitConfig,
+    buckets: HashMap<(LimitClass, IpAddr), Bucket>,
+    pruned: Instant,
+}
+
+impl RateLimiter {
+    fn new(limits: RateLimitConfig) -> Self {
+        RateLimiter {
+            limits,
+            buckets: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or says how long until there is one.
+    fn take(&mut self, class: LimitClass, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let limits = &self.limits;
+        let limit = class.limit(limits);
+        if now.duration_since(self.pruned) > Duration::from_secs(60) {
+            self.buckets.retain(|(class, _), bucket| {
+                let limit = class.limit(limits);
+                bucket.refill(limit, now) < f64::from(limit.burst)
+            });
+            self.pruned = now;
+        }
+        let bucket = self.buckets.entry((class, ip)).or_insert(Bucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        });
+        bucket.tokens = bucket.refill(limit, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let rate = f64::from(limit.per_minute) / 60.0;
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Which bucket a request draws from, none for cheap and operational routes.
+fn limit_class(state: &AppState, req: &Request) -> Option<LimitClass> {
+    let class = match route(req).as_str() {
+        "/style.css" | "/favicon.ico" | "/robots.txt" | "/metrics" | "/healthz" | "/readyz" => {
+            return None;
+        }
+        "/imgs" | "/media" | "/shorthand" | "/img/{width}/{name}" => LimitClass::Images,
+        "/" | "/tags" | "/topics" | "/authors" | "/authors/{slug}" | "/podcast" => {
+            LimitClass::Lists
+        }
+        r if r.starts_with("/archive") => LimitClass::Lists,
+        "/{*id}" => {
+            // the nested router already took off --base-path
+            let key = req.uri().path().trim_matches('/');
+            // anything that isn't a story is a tag or section listing
+            match state.db.contains_key(key) {
+                Ok(true) => LimitClass::Articles,
+                _ => LimitClass::Lists,
+            }
+        }
+        _ => LimitClass::Articles,
+    };
+    (class.limit(&config().rate_limit).per_minute > 0).then_some(class)
+}
+
+/// The client's address: the peer, or behind trusted proxies the last
+/// untrusted hop in `X-Forwarded-For`. None on a Unix socket without one.
+fn client_ip(req: &Request, config: &SiteConfig) -> Option<IpAddr> {
+    let trusted = |ip: &IpAddr| config.is_trusted_proxy(ip);
+    let peer = peer_ip(req);
+    if let Some(ip) = peer
+        && !trusted(&ip)
+    {
+        return Some(ip);
+    }
+    let hops: Vec<IpAddr> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect();
+    hops.iter()
+        .rev()
+        .find(|ip| !trusted(ip))
+        .or(hops.first())
+        .copied()
+        .or(peer)
+}
+
+/// `ip`, or its /64 for IPv6, where one client usually holds the whole range.
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        v4 => v4,
+    }
+}
+
+/// The connecting address, none on a Unix socket.
+fn peer_ip(req: &Request) -> Option<IpAddr> {
+    req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip().to_canonical())
+}
+
+fn is_allowed_bot(req: &Request, ip: IpAddr) -> bool {
+    let Some(agent) = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    config().rate_limit.bots.iter().any(|bot| {
+        agent.contains(&bot.user_agent)
+            && (bot.networks.is_empty() || bot.networks.iter().any(|net| net.contains(&ip)))
+    })
+}
+
+/// Answers 429 with `Retry-After` once a client has used up its bucket.
+async fn rate_limit(State(state): State<AppState>, req: Request, next: Next) -> Response<Body> {
+    let Some(class) = limit_class(&state, &req) else {
+        return next.run(req).await;
+    };
+    let Some(ip) = client_ip(&req, config()) else {
+        return next.run(req).await;
+    };
+    if is_allowed_bot(&req, ip) {
+        return next.run(req).await;
+    }
+    let taken = state
+        .buckets
+        .lock()
+        .unwrap()
+        .take(class, bucket_key(ip), Instant::now());
+    match taken {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            RATE_LIMITED.with_label_values(&[class.name()]).inc();
+            let retry_after = wait.as_secs_f64().ceil().max(1.0).to_string();
+            let headers = [
+                (header::RETRY_AFTER, retry_after),
+                (header::CACHE_CONTROL, "no-store".to_owned()),
+            ];
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                "Too many requests\n",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Serves `list` and `page` from the page cache, filling it on a miss.
+async fn page_cache(
+    State(state): State<AppState>,
+    Query(params): Query<SiteParams>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let seq = match write_seq(&state.meta) {
+        Ok(seq) if ARGS.page_cache > 0 => seq,
+        Ok(_) => return next.run(req).await,
+        Err(e) => {
+            error!("can't read write seq: {e}");
+            return next.run(req).await;
+        }
+    };
+    let key = format!(
+        "{}?page={}",
+        req.uri().path(),
+        params.page.unwrap_or_default()
+    );
+
+    let hit = state.pages.lock().unwrap().get(seq, &key);
+    if let Some((headers, body)) = hit {
+        if let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok())
+            && is_fresh(req.headers(), etag)
+        {
+            return not_modified(etag);
+        }
+        let mut resp = Response::new(Body::from(body));
+        *resp.headers_mut() = headers;
+        return resp;
+    }
+
+    let resp = next.run(req).await;
+    if resp.status() != StatusCode::OK {
+        return resp;
+    }
+    let (parts, body) = resp.into_parts();
+    match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => {
+            let headers = parts.headers.clone();
+            state
+                .pages
+                .lock()
+                .unwrap()
+                .put(seq, key, headers, body.clone());
+            Response::from_parts(parts, Body::from(body))
+        }
+        Err(e) => {
+            error!("can't buffer {key}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "list.html")]
+struct PageList {
+    items: Vec<Item>,
+    page: usize,
+    url_path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Episode {
+    id: String,
+    title: String,
+    display_date: String,
+    description: String,
+    audio: Option<String>,
+    audio_type: String,
+    duration: Option<String>,
+    image: Option<String>,
+}
+
+impl Episode {
+    fn new(id: &str, item: &rss::Item) -> Self {
+        let display_date = episode_date(item)
+            .and_then(|d| d.parse::<Timestamp>().ok())
+            .map(|ts| ts.to_zoned(TimeZone::UTC).strftime("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        let itunes = item.itunes_ext();
+        let description = sanitize_html(
+            item.content()
+                .or(item.description())
+                .or_else(|| itunes.and_then(|e| e.summary()))
+                .unwrap_or_default(),
+        );
+        let media_folder = PathBuf::from(&ARGS.data).join("media");
+        let audio = item.enclosure().and_then(|e| {
+            let name = get_filename_from_url(e.url());
+            media_folder
+                .join(&name)
+                .exists()
+                .then(|| format!("/media/{name}"))
+        });
+
+        Episode {
+            id: id.to_owned(),
+            title: item.title().unwrap_or_default().to_owned(),
+            display_date,
+            description,
+            audio,
+            audio_type: item
+                .enclosure()
+                .map(|e| e.mime_type().to_owned())
+                .unwrap_or_default(),
+            duration: itunes.and_then(|e| e.duration()).map(|d| d.to_owned()),
+            image: itunes
+                .and_then(|e| e.image())
+                .map(|url| format!("/imgs/{}", get_filename_from_url(url))),
+        }
+    }
+}
+
+const EPISODE_PREFIX: &[u8] = b"episode|";
+
+/// Episodes from newest to oldest, as (id, item).
+fn episodes(podcast: &PartitionHandle) -> impl DoubleEndedIterator<Item = (String, rss::Item)> {
+    podcast.prefix(EPISODE_PREFIX).rev().filter_map(|i| {
+        let (k, v) = i.ok()?;
+        let id = String::from_utf8_lossy(&k[EPISODE_PREFIX.len() + 8..]).into_owned();
+        let item = serde_json::from_slice(&v).ok()?;
+        Some((id, item))
+    })
+}
+
+fn channel(podcast: &PartitionHandle) -> Option<rss::Channel> {
+    let v = podcast.get("channel").ok()??;
+    serde_json::from_slice(&v).ok()
+}
+
+#[derive(Template)]
+#[template(path = "podcast.html")]
+struct PodcastList {
+    title: String,
+    description: String,
+    image: Option<String>,
+    episodes: Vec<Episode>,
+    page: usize,
+    url_path: String,
+}
+
+async fn podcast_list(
+    Query(params): Query<SiteParams>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    let channel = channel(&state.podcast).ok_or(AppError::NotFound)?;
+    let page = params.page.unwrap_or_default();
+    let (n, end) = page_range(page)?;
+    let episodes = episodes(&state.podcast)
+        .skip(n)
+        .take(end - n)
+        .map(|(id, item)| Episode::new(&id, &item))
+        .collect();
+    let image = channel
+        .itunes_ext()
+        .and_then(|e| e.image())
+        .or_else(|| channel.image().map(|i| i.url()))
+        .map(|url| format!("/imgs/{}", get_filename_from_url(url)));
+
+    let podcast_list = PodcastList {
+        title: channel.title,
+        description: sanitize_html(&channel.description),
+        image,
+        episodes,
+        page,
+        url_path: "/podcast".to_owned(),
+    };
+    into_cached_response(&podcast_list, config().cache.list_max_age)
+}
+
+#[derive(Template)]
+#[template(path = "episode.html")]
+struct EpisodePage {
+    podcast: String,
+    episode: Episode,
+}
+
+async fn episode(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    let (id, item) = episodes(&state.podcast)
+        .find(|(i, _)| *i == id)
+        .ok_or(AppError::NotFound)?;
+    let podcast = channel(&state.podcast).map(|c| c.title).unwrap_or_default();
+    let episode = EpisodePage {
+        podcast,
+        episode: Episode::new(&id, &item),
+    };
+    into_cached_response(&episode, config().cache.list_max_age)
+}
+
+/// The podcast feed, with audio and artwork pointing at this mirror.
+async fn podcast_feed(State(state): State<AppState>) -> Result<Response<Body>, AppError> {
+    let mut channel = channel(&state.podcast).ok_or(AppError::NotFound)?;
+    let base = base_url();
+    let local_img = |url: &str| format!("{base}/imgs/{}", get_filename_from_url(url));
+
+    channel.link = format!("{base}/podcast");
+    if let Some(img) = channel.image.as_mut() {
+        img.url = local_img(&img.url);
+        img.link = channel.link.clone();
+    }
+    if let Some(img) = channel.itunes_ext.as_mut().and_then(|e| e.image.as_mut()) {
+        *img = local_img(img);
+    }
+    channel.items = episodes(&state.podcast)
+        .map(|(id, mut item)| {
+            item.link = Some(format!("{base}/podcast/{id}"));
+            if let Some(e) = item.enclosure.as_mut() {
+                e.url = format!("{base}/media/{}", get_filename_from_url(&e.url));
+            }
+            if let Some(img) = item.itunes_ext.as_mut().and_then(|e| e.image.as_mut()) {
+                *img = local_img(img);
+            }
+            item
+        })
+        .collect();
+
+    let headers = [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")];
+    Ok((headers, channel.to_string()).into_response())
+}
+
+async fn robots() -> impl IntoResponse {
+    let base = base_url();
+    let headers = [(header::CONTENT_TYPE, "text/plain; charset=utf-8")];
+    let body = format!("User-agent: *\nAllow: /\n\nSitemap: {base}/sitemap.xml\n");
+    (headers, body)
+}
+
+/// Urls per sitemap file, the protocol's limit.
+const SITEMAP_URLS: usize = 50_000;
+
+const SITEMAP_HEAD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+"#;
+
+/// Sitemap index pointing at the `stories-{n}.xml` and `tags-{n}.xml` files.
+async fn sitemap_index(State(state): State<AppState>) -> Result<Response<Body>, AppError> {
+    let base = base_url();
+    // never less than the real count, as index keys are only ever added, so at
+    // worst the last file is empty; counting exactly would scan the index
+    let stories = state.index.approximate_len();
+    let tags = tag_paths(state.tags).count();
+
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+"#,
+    );
+    for (kind, len) in [("stories", stories), ("tags", tags)] {
+        for n in 0..len.div_ceil(SITEMAP_URLS) {
+            xml.push_str(&format!(
+                "  <sitemap><loc>{base}/sitemaps/{kind}-{n}.xml</loc></sitemap>\n"
+            ));
+        }
+    }
+    xml.push_str("</sitemapindex>\n");
+
+    let headers = [(header::CONTENT_TYPE, "application/xml; charset=utf-8")];
+    Ok((headers, xml).into_response())
+}
+
+/// One sitemap file, streamed straight from `index` or `tags`.
+async fn sitemap(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Response<Body>, AppError> {
+    let base = base_url();
+    let (kind, n) = name
+        .strip_suffix(".xml")
+        .and_then(|s| s.split_once('-'))
+        .ok_or(AppError::NotFound)?;
+    let n: usize = n.parse().map_err(|_| AppError::NotFound)?;
+
+    let urls: Box<dyn Iterator<Item = String> + Send> = match kind {
+        "stories" => Box::new(story_urls(state.index, base, n)),
+        "tags" => Box::new(
+            tag_paths(state.tags)
+                .skip(n * SITEMAP_URLS)
+                .take(SITEMAP_URLS)
+                .map(move |(path, secs)| sitemap_url(&base, &path, secs)),
+        ),
+        _ => return Err(AppError::NotFound),
+    };
+    let xml = std::iter::once(SITEMAP_HEAD.to_owned())
+        .chain(urls)
+        .chain(std::iter::once("</urlset>\n".to_owned()))
+        .map(Ok::<_, Infallible>);
+
+    let headers = [(header::CONTENT_TYPE, "application/xml; charset=utf-8")];
+    Ok((headers, Body::from_stream(stream::iter(xml))).into_response())
+}
+
+fn sitemap_url(base: &str, key: &str, secs: i64) -> String {
+    let loc: Vec<_> = key.split('/').map(urlencoding::encode).collect();
+    let lastmod = Timestamp::from_second(secs).unwrap_or_default();
+    format!(
+        "  <url><loc>{base}/{}</loc><lastmod>{lastmod}</lastmod></url>\n",
+        loc.join("/")
+    )
+}
+
+/// Sitemap entries of the `n`th stories file, read from `index` in chunks so no
+/// iterator is held across polls.
+fn story_urls(index: PartitionHandle, base: String, n: usize) -> impl Iterator<Item = String> {
+    let mut last: Option<Vec<u8>> = None;
+    let mut left = SITEMAP_URLS;
+    std::iter::from_fn(move || {
+        let keys: Box<dyn Iterator<Item = _>> = match &last {
+            None => Box::new(index.iter().skip(n * SITEMAP_URLS)),
+            Some(k) => Box::new(index.range((Bound::Excluded(k.clone()), Bound::Unbounded))),
+        };
+        let mut chunk = String::new();
+        let mut read = 0;
+        for kv in keys.take(left.min(1000)) {
+            let (k, _) = kv.inspect_err(|e| error!("sitemap: {e}")).ok()?;
+            read += 1;
+            if let Some(ts) = k.get(..8) {
+                let secs = i64::from_be_bytes(ts.try_into().unwrap_or_default());
+                chunk.push_str(&sitemap_url(&base, &String::from_utf8_lossy(&k[8..]), secs));
+            }
+            last = Some(k.to_vec());
+        }
+        left -= read;
+        (read > 0).then_some(chunk)
+    })
+}
+
+/// Every tag path in `tags` with the timestamp of its newest story, found by
+/// seeking past each path instead of scanning all its stories.
+fn tag_paths(tags: PartitionHandle) -> impl Iterator<Item = (String, i64)> + Send {
+    let mut start = Vec::new();
+    std::iter::from_fn(move || {
+        let (k, _) = tags.range(start.clone()..).next()?.ok()?;
+        let sep = k.iter().position(|b| *b == b'|')?;
+        let path = &k[..sep + 1];
+        let (newest, _) = tags.prefix(path).next_back()?.ok()?;
+        let secs = i64::from_be_bytes(newest.get(sep + 1..sep + 9)?.try_into().ok()?);
+
+        // '}' sorts right after '|'
+        start = k[..sep].to_vec();
+        start.push(b'}');
+        Some((String::from_utf8_lossy(&k[..sep]).into_owned(), secs))
+    })
+}
+
+/// Route pattern of a request, for metrics and rate limits.
+fn route(req: &Request) -> String {
+    match req.extensions().get::<MatchedPath>() {
+        // keep labels independent of --base-path
+        Some(p) => match p.as_str().strip_prefix(base_path()) {
+            Some("") => "/".to_owned(),
+            Some(r) => r.to_owned(),
+            None => p.as_str().to_owned(),
+        },
+        None => {
+            // nested file services have no matched path, and inside the
+            // nested router the uri is already without --base-path
+            let prefix = req.uri().path().split('/').nth(1).unwrap_or_default();
+            match prefix {
+                "imgs" | "media" | "shorthand" => format!("/{prefix}"),
+                _ => "fallback".to_owned(),
+            }
+        }
+    }
+}
+
+/// Counts and times every request by its route pattern.
+async fn track(req: Request, next: Next) -> Response<Body> {
+    let route = route(&req);
+    let start = Instant::now();
+    let resp = next.run(req).await;
+    if resp.status() == StatusCode::NOT_FOUND {
+        NOT_FOUND.inc();
+    }
+    HTTP_DURATION
+        .with_label_values(&[&route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&route, resp.status().as_str()])
+        .inc();
+    resp
+}
+
+async fn metrics(State(state): State<AppState>) -> Result<Response<Body>, AppError> {
+    for p in [
+        &state.db,
+        &state.index,
+        &state.tags,
+        &state.tag_counts,
+        &state.authors,
+        &state.podcast,
+        &state.misses,
+        &state.summaries,
+        &state.meta,
+    ] {
+        let name: &str = &p.name;
+        PARTITION_BYTES
+            .with_label_values(&[name])
+            .set(p.disk_space() as i64);
+        PARTITION_KEYS
+            .with_label_values(&[name])
+            .set(p.approximate_len() as i64);
+    }
+    // a full count would scan the whole index on every scrape
+    STORIES.set(state.index.approximate_len() as i64);
+
+    let mut buf = vec![];
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        error!("can't encode metrics: {e}");
+    }
+    let headers = [(header::CONTENT_TYPE, encoder.format_type().to_owned())];
+    Ok((headers, buf).into_response())
+}
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    terminated().await;
+    info!("Shutting down");
+}
+
+/// Reloads the certificate and key on SIGHUP, e.g. after a renewal.
+#[cfg(unix)]
+async fn reload_tls(config: RustlsConfig) {
+    let mut hup = signal(SignalKind::hangup()).unwrap();
+    while hup.recv().await.is_some() {
+        let (Some(cert), Some(key)) = (&ARGS.tls_cert, &ARGS.tls_key) else {
+            return;
+        };
+        match config.reload_from_pem_file(cert, key).await {
+            Ok(()) => info!("Reloaded TLS certificate"),
+            Err(e) => error!("Failed to reload TLS certificate: {e}"),
+        }
+    }
+}
+
+/// Wakes `crawl_loop` from its wait on shutdown.
+static CRAWL_STOP: Notify = Notify::const_new();
+
+/// Crawls now and then again `interval` plus jitter after each crawl ends, until
+/// `crawl::stop`. A failed crawl is logged and retried next time.
+async fn crawl_loop(keyspace: Keyspace, interval: SignedDuration) {
+    loop {
+        if let Err(e) = crawl::run(&keyspace).await {
+            error!("Crawl failed: {e}");
+        }
+        if crawl::stopped() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(crawl::next_wait(interval, ARGS.crawl_jitter)) => {}
+            _ = CRAWL_STOP.notified() => return,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "status.html")]
+struct StatusPage {
+    runs: Vec<RunRow>,
+}
+
+struct RunRow {
+    start: String,
+    took: String,
+    new: u64,
+    failures: u64,
+    error: Option<String>,
+}
+
+/// Crawls shown on /status.
+const STATUS_RUNS: usize = 50;
+
+/// The latest crawls, newest first, from the `runs` partition.
+async fn status(State(state): State<AppState>) -> Result<Response<Body>, AppError> {
+    let mut runs = vec![];
+    for i in state.runs.iter().rev().take(STATUS_RUNS) {
+        let (k, v) = i?;
+        let run: Run = match serde_json::from_slice(&v) {
+            Ok(run) => run,
+            Err(e) => {
+                warn!("invalid run {k:?}: {e}");
+                continue;
+            }
+        };
+        let took = run.end.duration_since(run.start).round(Unit::Second);
+        runs.push(RunRow {
+            start: run.start.strftime("%Y-%m-%d %H:%M").to_string(),
+            took: took.map(|d| format!("{d:#}")).unwrap_or_default(),
+            new: run.new,
+            failures: run.failures,
+            error: run.error,
+        });
+    }
+    into_response(&StatusPage { runs })
+}
+
+async fn healthz() -> impl IntoResponse {
+    (NO_STORE, "ok\n")
+}
+
+/// Whether the keyspace is readable and the archive fresh, judged by the newest
+/// story in `index`.
+async fn readyz(State(state): State<AppState>) -> Response<Body> {
+    let newest = match state.index.last_key_value() {
+        Ok(kv) => kv.and_then(|(k, _)| {
+            let secs = i64::from_be_bytes(k.get(..8)?.try_into().ok()?);
+            Timestamp::from_second(secs).ok()
+        }),
+        Err(e) => {
+            error!("readyz: {e}");
+            let body = Json(json!({ "status": "error", "error": e.to_string() }));
+            return (StatusCode::SERVICE_UNAVAILABLE, NO_STORE, body).into_response();
+        }
+    };
+
+    let age = newest.map(|ts| Timestamp::now().as_second() - ts.as_second());
+    let stale_after = ARGS.stale_after as i64 * 3600;
+    let status = match age {
+        None => "empty",
+        Some(age) if stale_after > 0 && age > stale_after => "stale",
+        Some(_) => "ok",
+    };
+    let body = Json(json!({
+        "status": status,
+        "newest_story": newest.map(|ts| ts.to_string()),
+        "age_seconds": age,
+        "stale_after_seconds": stale_after,
+    }));
+    let code = if status == "ok" {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, NO_STORE, body).into_response()
+}
+
+const NO_STORE: [(header::HeaderName, &str); 1] = [(header::CACHE_CONTROL, "no-store")];
+
+/// `--base-url`, or else one made of `--addr`. Never the request's `Host`, which
+/// would let a client put its own into pages and feeds that caches share.
+fn base_url() -> String {
+    static BASE_URL: LazyLock<String> = LazyLock::new(|| match &ARGS.base_url {
+        Some(base) => base.trim_end_matches('/').to_owned(),
+        None => {
+            let scheme = if ARGS.tls_cert.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            format!("{scheme}://{}{}", ARGS.addr, base_path())
+        }
+    });
+    BASE_URL.clone()
+}
+
+fn into_response<T: Template>(t: &T) -> Result<Response<Body>, AppError> {
+    Ok(Html(t.render()?).into_response())
+}
+
+fn into_cached_response<T: Template>(t: &T, max_age: u32) -> Result<Response<Body>, AppError> {
+    let mut resp = into_response(t)?;
+    let value = HeaderValue::from_str(&format!("public, max-age={max_age}")).unwrap();
+    resp.headers_mut().insert(header::CACHE_CONTROL, value);
+    Ok(resp)
+}
+
+/// Whether the client's copy is still current, by `If-None-Match`. Pages change
+/// with their surroundings, so `If-Modified-Since` alone never matches.
+fn is_fresh(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(tags) = headers.get(header::IF_NONE_MATCH) else {
+        return false;
+    };
+    let tags = tags.to_str().unwrap_or_default();
+    tags.split(',')
+        .map(str::trim)
+        .any(|t| t == etag || t == "*")
+}
+
+fn not_modified(etag: &str) -> Response<Body> {
+    let headers = [
+        (header::ETAG, HeaderValue::from_str(etag).unwrap()),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_str(&format!(
+                "public, max-age={}",
+                config().cache.article_max_age
+            ))
+            .unwrap(),
+        ),
+    ];
+    (StatusCode::NOT_MODIFIED, headers).into_response()
+}
+
+fn http_date(ts: Timestamp) -> Result<HeaderValue, Box<dyn std::error::Error>> {
+    let date = jiff::fmt::rfc2822::DateTimePrinter::new().timestamp_to_rfc9110_string(&ts)?;
+    Ok(HeaderValue::from_str(&date)?)
+}
+
+/// Local images and media never change under a given name.
+async fn immutable<B>(mut resp: Response<B>) -> Response<B> {
+    if resp.status().is_success() {
+        resp.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_str(&format!(
+                "public, max-age={}, immutable",
+                config().cache.media_max_age
+            ))
+            .unwrap(),
+        );
+    }
+    resp
+}
+
+/// Mirrored Shorthand pages run their own scripts, so they get an opaque
+/// origin and may only load what was archived with them.
+async fn sandbox<B>(mut resp: Response<B>) -> Response<B> {
+    resp.headers_mut().insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_str(&config().shorthand_csp).unwrap(),
+    );
+    resp
+}
+
+fn asset_cache_control() -> HeaderValue {
+    let cache = &config().cache;
+    HeaderValue::from_str(&format!(
+        "public, max-age={}, s-maxage={}",
+        cache.asset_max_age, cache.asset_s_maxage
+    ))
+    .unwrap()
+}
+
+async fn style() -> impl IntoResponse {
+    let headers = [
+        (header::CONTENT_TYPE, HeaderValue::from_static("text/css")),
+        (header::CACHE_CONTROL, asset_cache_control()),
+    ];
+
+    (headers, include_str!("../../static/style.css"))
+}
+
+async fn favicon() -> impl IntoResponse {
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("image/x-icon"),
+        ),
+        (header::CACHE_CONTROL, asset_cache_control()),
+    ];
 
     (headers, include_bytes!("../../static/favicon.ico"))
 }
+
+/// Widths served under `/img/{width}/`, anything else is a 404 so the cache stays bounded.
+const IMG_WIDTHS: [u32; 3] = [320, 640, 1280];
+
+/// `srcset` for a local `/imgs/` url with the widths narrower than the image,
+/// empty if it can't be resized.
+fn srcset(url: &str) -> String {
+    let Some(name) = url.strip_prefix("/imgs/") else {
+        return String::new();
+    };
+    let Some(width) = resizable_width(name) else {
+        return String::new();
+    };
+    IMG_WIDTHS
+        .iter()
+        .filter(|w| **w < width)
+        .map(|w| format!("{}/img/{w}/{name} {w}w", base_path()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Width of an image in `imgs/` that can become a jpeg without losing more than
+/// detail, none for transparent, possibly animated or missing ones. Images never
+/// change under a name, so each is only read once.
+fn resizable_width(name: &str) -> Option<u32> {
+    static WIDTHS: LazyLock<Mutex<HashMap<String, Option<u32>>>> = LazyLock::new(Default::default);
+    if let Some(width) = WIDTHS.lock().unwrap().get(name) {
+        return *width;
+    }
+    let path = PathBuf::from(&ARGS.data).join("imgs").join(name);
+    let width = opaque_still_width(&path);
+    // one that isn't there yet may be downloaded later
+    if width.is_some() || path.exists() {
+        WIDTHS.lock().unwrap().insert(name.to_owned(), width);
+    }
+    width
+}
+
+/// Reads just the image header.
+fn opaque_still_width(path: &path::Path) -> Option<u32> {
+    let reader = ImageReader::open(path).ok()?.with_guessed_format().ok()?;
+    match reader.format()? {
+        ImageFormat::Gif => return None,
+        ImageFormat::WebP => {
+            let file = BufReader::new(File::open(path).ok()?);
+            if WebPDecoder::new(file).ok()?.has_animation() {
+                return None;
+            }
+        }
+        _ => {}
+    }
+    let decoder = reader.into_decoder().ok()?;
+    if decoder.color_type().has_alpha() {
+        return None;
+    }
+    Some(decoder.dimensions().0)
+}
+
+/// Downscaled jpeg of an image in `imgs/`, generated on first request and
+/// cached under `thumbs/{width}/`.
+async fn img(Path((width, name)): Path<(u32, String)>) -> Response<Body> {
+    if !IMG_WIDTHS.contains(&width) || !is_safe_filename(&name) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let folder = PathBuf::from(&ARGS.data);
+    let src = folder.join("imgs").join(&name);
+    if !src.exists() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    // not in any srcset, flattening or upscaling would only make it worse
+    if resizable_width(&name).is_none_or(|w| width >= w) {
+        return Redirect::temporary(&url(&format!("/imgs/{name}"))).into_response();
+    }
+    let cached = folder
+        .join("thumbs")
+        .join(width.to_string())
+        .join(format!("{name}.jpg"));
+
+    // decoding takes the whole image in memory, so only a few at a time
+    static RESIZES: LazyLock<Semaphore> = LazyLock::new(|| {
+        Semaphore::new(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    });
+    let _permit = if cached.exists() {
+        None
+    } else {
+        RESIZES.acquire().await.ok()
+    };
+    let result = tokio::task::spawn_blocking(move || -> ImageResult<Vec<u8>> {
+        if !cached.exists() {
+            resize_img(&src, &cached, width)?;
+        }
+        Ok(std::fs::read(&cached)?)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(bytes)) => {
+            let headers = [
+                (header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg")),
+                (header::CACHE_CONTROL, asset_cache_control()),
+            ];
+            (headers, bytes).into_response()
+        }
+        Ok(Err(e)) => {
+            warn!("can't resize {name} to {width}: {e}");
+            Redirect::temporary(&url(&format!("/imgs/{name}"))).into_response()
+        }
+        Err(e) => {
+            error!("resize task for {name} failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn resize_img(src: &path::Path, dst: &path::Path, width: u32) -> ImageResult<()> {
+    let mut img = image::open(src)?;
+    if img.width() > width {
+        img = img.resize(width, u32::MAX, FilterType::Triangle);
+    }
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // concurrent requests for the same image each write their own file
+    static TMP_SEQ: AtomicUsize = AtomicUsize::new(0);
+    let seq = TMP_SEQ.fetch_add(1, Ordering::Relaxed);
+    let tmp = dst.with_extension(format!("{seq}.tmp"));
+    img.to_rgb8().save_with_format(&tmp, ImageFormat::Jpeg)?;
+    std::fs::rename(tmp, dst)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_minute: u32, burst: u32) -> RateLimiter {
+        let limit = Limit { per_minute, burst };
+        RateLimiter::new(RateLimitConfig {
+            lists: limit,
+            articles: limit,
+            images: limit,
+            ..Default::default()
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills_at_rate() {
+        let mut limiter = limiter(60, 3);
+        let t0 = Instant::now();
+        let a = ip("192.0.2.1");
+        for _ in 0..3 {
+            assert_eq!(limiter.take(LimitClass::Lists, a, t0), Ok(()));
+        }
+        let wait = limiter.take(LimitClass::Lists, a, t0).unwrap_err();
+        assert!((wait.as_secs_f64() - 1.0).abs() < 1e-6, "{wait:?}");
+
+        let half = t0 + Duration::from_millis(500);
+        let wait = limiter.take(LimitClass::Lists, a, half).unwrap_err();
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-6, "{wait:?}");
+
+        let later = t0 + Duration::from_secs(1);
+        assert_eq!(limiter.take(LimitClass::Lists, a, later), Ok(()));
+        assert!(limiter.take(LimitClass::Lists, a, later).is_err());
+    }
+
+    #[test]
+    fn bucket_refills_no_further_than_burst() {
+        let mut limiter = limiter(60, 2);
+        let t0 = Instant::now();
+        let a = ip("192.0.2.1");
+        limiter.take(LimitClass::Lists, a, t0).unwrap();
+        let hour = t0 + Duration::from_secs(3600);
+        for _ in 0..2 {
+            assert_eq!(limiter.take(LimitClass::Lists, a, hour), Ok(()));
+        }
+        assert!(limiter.take(LimitClass::Lists, a, hour).is_err());
+    }
+
+    #[test]
+    fn buckets_are_per_class_and_client() {
+        let mut limiter = limiter(1, 1);
+        let now = Instant::now();
+        let (a, b) = (ip("192.0.2.1"), ip("192.0.2.2"));
+        assert_eq!(limiter.take(LimitClass::Lists, a, now), Ok(()));
+        assert!(limiter.take(LimitClass::Lists, a, now).is_err());
+        assert_eq!(limiter.take(LimitClass::Articles, a, now), Ok(()));
+        assert_eq!(limiter.take(LimitClass::Images, a, now), Ok(()));
+        assert_eq!(limiter.take(LimitClass::Lists, b, now), Ok(()));
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let mut limiter = limiter(60, 5);
+        let t0 = limiter.pruned;
+        let (a, b, c) = (ip("192.0.2.1"), ip("192.0.2.2"), ip("192.0.2.3"));
+        limiter.take(LimitClass::Lists, a, t0).unwrap();
+        // b is drained just before the next prune, a has long refilled
+        let t1 = t0 + Duration::from_secs(60);
+        for _ in 0..5 {
+            limiter.take(LimitClass::Lists, b, t1).unwrap();
+        }
+        let t2 = t1 + Duration::from_millis(500);
+        limiter.take(LimitClass::Lists, c, t2).unwrap();
+        let mut left: Vec<_> = limiter.buckets.keys().map(|(_, ip)| *ip).collect();
+        left.sort();
+        assert_eq!(left, [b, c]);
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        assert_eq!(bucket_key(ip("2001:db8:1:2:aaaa::1")), ip("2001:db8:1:2::"));
+        assert_ne!(
+            bucket_key(ip("2001:db8:1:2::1")),
+            bucket_key(ip("2001:db8:1:3::1"))
+        );
+        assert_eq!(bucket_key(ip("192.0.2.1")), ip("192.0.2.1"));
+    }
+
+    fn request(peer: Option<&str>, forwarded: &[&str]) -> Request {
+        let mut req = Request::new(Body::empty());
+        if let Some(peer) = peer {
+            let addr = SocketAddr::new(ip(peer), 40000);
+            req.extensions_mut().insert(ConnectInfo(addr));
+        }
+        for v in forwarded {
+            req.headers_mut()
+                .append("x-forwarded-for", HeaderValue::from_str(v).unwrap());
+        }
+        req
+    }
+
+    #[test]
+    fn client_ip_walks_forwarded_for() {
+        let mut config = SiteConfig::default();
+        config.trusted_proxies.push("10.0.0.0/8".parse().unwrap());
+        let client = |peer, forwarded: &[&str]| client_ip(&request(peer, forwarded), &config);
+
+        // untrusted peers can't pick their address
+        assert_eq!(
+            client(Some("198.51.100.7"), &["1.1.1.1"]),
+            Some(ip("198.51.100.7"))
+        );
+        // behind a trusted proxy, the last hop it saw
+        assert_eq!(client(Some("127.0.0.1"), &["1.1.1.1"]), Some(ip("1.1.1.1")));
+        // the client can prepend anything, only the rightmost untrusted hop counts
+        assert_eq!(
+            client(Some("127.0.0.1"), &["6.6.6.6, 1.1.1.1"]),
+            Some(ip("1.1.1.1"))
+        );
+        // through a chain of trusted proxies, across header lines
+        assert_eq!(
+            client(Some("10.0.0.1"), &["6.6.6.6, 1.1.1.1", "10.0.0.2"]),
+            Some(ip("1.1.1.1"))
+        );
+        assert_eq!(
+            client(Some("127.0.0.1"), &["1.1.1.1, nonsense"]),
+            Some(ip("1.1.1.1"))
+        );
+        // all trusted: the first hop
+        assert_eq!(
+            client(Some("127.0.0.1"), &["10.0.0.3, 10.0.0.2"]),
+            Some(ip("10.0.0.3"))
+        );
+        // a trusted peer without the header is the client
+        assert_eq!(client(Some("127.0.0.1"), &[]), Some(ip("127.0.0.1")));
+        assert_eq!(client(Some("::ffff:192.0.2.1"), &[]), Some(ip("192.0.2.1")));
+        // Unix socket peers are trusted, and unknown without the header
+        assert_eq!(client(None, &["1.1.1.1"]), Some(ip("1.1.1.1")));
+        assert_eq!(client(None, &[]), None);
+    }
+}
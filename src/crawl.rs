@@ -0,0 +1,1201 @@
+use crate::{
+    CDN_DOMAIN, Item, TagCount, author_slug, bump_write_seq, episode_date, episode_id,
+    get_filename_from_url, is_safe_filename, kv_sep_partition_option, meta_partition, mp4_streams,
+    promo_video, run_partition, shorthand_dir, summary_partition, tag_key, vtt_subtitles,
+};
+use fjall::{Keyspace, PartitionCreateOptions, PartitionHandle};
+use jiff::{SignedDuration, Timestamp, Unit};
+use prometheus::{
+    Gauge, GaugeVec, IntCounter, IntCounterVec, register_gauge, register_gauge_vec,
+    register_int_counter, register_int_counter_vec,
+};
+use reqwest::{Proxy, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::{
+        LazyLock, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, instrument, warn};
+use urlencoding::encode;
+
+/// Error of a crawl step, which can be sent on from the crawl task.
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Crawl options, shared by `spider` and `web --crawl-interval`.
+#[derive(clap::Args, Debug, Clone)]
+pub struct CrawlArgs {
+    /// proxy (e.g., http://127.0.0.1:8089)
+    #[clap(long)]
+    pub proxy: Option<String>,
+    /// skip video renditions larger than this (MB), 0 to not download videos
+    #[arg(long, default_value_t = 200)]
+    pub max_video_size: u64,
+    /// podcast RSS feed to archive (e.g., https://feeds.acast.com/public/shows/here-there)
+    #[arg(long)]
+    pub podcast_feed: Option<String>,
+    /// also mirror Shorthand stories with their css, fonts, images and videos
+    #[arg(long)]
+    pub shorthand: bool,
+    /// page through every section to the end instead of stopping at the first
+    /// page without new stories, e.g. to finish an interrupted first crawl
+    #[arg(long)]
+    pub full: bool,
+}
+
+/// Data folder and options, set once by `init`.
+static CONFIG: OnceLock<(PathBuf, CrawlArgs)> = OnceLock::new();
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    let mut client_builder = reqwest::Client::builder();
+    if let Some(proxy) = &args().proxy {
+        client_builder = client_builder.proxy(Proxy::all(proxy).unwrap());
+    }
+    client_builder
+        .danger_accept_invalid_certs(true)
+        .timeout(std::time::Duration::from_secs(30))
+        .build()
+        .unwrap()
+});
+
+static STORIES_FETCHED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "whynot_spider_stories_fetched_total",
+        "Stories fetched from the source, by section",
+        &["section"]
+    )
+    .unwrap()
+});
+
+static STORIES_NEW: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "whynot_spider_stories_new_total",
+        "Stories added to the archive, by section",
+        &["section"]
+    )
+    .unwrap()
+});
+
+static IMAGES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("whynot_spider_images_downloaded_total", "Images downloaded").unwrap()
+});
+
+static BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "whynot_spider_downloaded_bytes_total",
+        "Bytes of images, media and assets downloaded"
+    )
+    .unwrap()
+});
+
+static RETRIES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "whynot_spider_retries_total",
+        "Downloads retried after an error"
+    )
+    .unwrap()
+});
+
+static FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "whynot_spider_failures_total",
+        "Downloads and fetches given up on"
+    )
+    .unwrap()
+});
+
+static SECTION_DURATION: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "whynot_spider_crawl_duration_seconds",
+        "How long the last crawl of each section took",
+        &["section"]
+    )
+    .unwrap()
+});
+
+static LAST_RUN: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "whynot_spider_last_run_timestamp_seconds",
+        "When the last crawl finished"
+    )
+    .unwrap()
+});
+
+/// Set by `stop`.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+const SECTIONS: [&str; 3] = ["/wainao-reads", "/english", "/wainao-watches"];
+
+/// One crawl, as kept in the `runs` partition.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Run {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// stories added
+    pub new: u64,
+    /// downloads and fetches given up on
+    pub failures: u64,
+    /// why the crawl ended early, if it did
+    pub error: Option<String>,
+}
+
+/// Sets the data folder and options for every later crawl, creating the media
+/// folders. Only the first call counts.
+pub fn init(data: &Path, args: CrawlArgs) -> Result<(), BoxError> {
+    // checked here so building CLIENT can't fail mid-crawl
+    if let Some(proxy) = &args.proxy {
+        Proxy::all(proxy)?;
+    }
+    for dir in ["imgs", "media", "shorthand"] {
+        create_dir_all(data.join(dir))?;
+    }
+    // listed from the start, not only once something is downloaded or fails
+    for counter in [&IMAGES, &BYTES, &RETRIES, &FAILURES] {
+        LazyLock::force(counter);
+    }
+    let _ = CONFIG.set((data.to_owned(), args));
+    Ok(())
+}
+
+fn args() -> &'static CrawlArgs {
+    &CONFIG.get().expect("crawl::init not called").1
+}
+
+/// `dir` under the data folder.
+fn data_dir(dir: &str) -> PathBuf {
+    CONFIG.get().expect("crawl::init not called").0.join(dir)
+}
+
+/// Asks the running crawl, if any, and every later one to stop once the batch
+/// being stored is committed.
+pub fn stop() {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+pub fn stopped() -> bool {
+    STOP.load(Ordering::Relaxed)
+}
+
+/// Time to wait before the next scheduled crawl: `interval` plus up to `jitter`
+/// at random, so crawls don't hit the site on the clock.
+pub fn next_wait(interval: SignedDuration, jitter: SignedDuration) -> Duration {
+    let wait = interval.unsigned_abs() + jitter.unsigned_abs().mul_f64(fastrand::f64());
+    if let Ok(shown) = SignedDuration::try_from(wait).and_then(|d| d.round(Unit::Second)) {
+        info!("Next crawl in {shown:#}");
+    }
+    wait
+}
+
+/// Crawls every section, the stories web missed and the podcast feed into
+/// `keyspace`, then records the run in `runs`. Errors are recorded and
+/// returned; the crawl never panics on remote data, as a release build aborts.
+pub async fn run(keyspace: &Keyspace) -> Result<(), BoxError> {
+    let start = Timestamp::now();
+    let (new, failures) = (stories_new(), FAILURES.get());
+    let result = match tokio::spawn(crawl(keyspace.clone())).await {
+        Ok(result) => result,
+        Err(e) => Err(e.into()),
+    };
+
+    let error = match &result {
+        Err(e) => Some(e.to_string()),
+        Ok(()) if stopped() => Some("stopped".to_owned()),
+        Ok(()) => None,
+    };
+    let run = Run {
+        start,
+        end: Timestamp::now(),
+        new: stories_new() - new,
+        failures: FAILURES.get() - failures,
+        error,
+    };
+    info!("Crawl done: {run:?}");
+    run_partition(keyspace)?.insert(start.as_second().to_be_bytes(), serde_json::to_vec(&run)?)?;
+    LAST_RUN.set(run.end.as_millisecond() as f64 / 1000.0);
+    result
+}
+
+/// Stories added by all crawls so far.
+fn stories_new() -> u64 {
+    SECTIONS
+        .iter()
+        .map(|s| s.trim_matches('/'))
+        .chain(["misses"])
+        .map(|l| STORIES_NEW.with_label_values(&[l]).get())
+        .sum()
+}
+
+/// Backfills authors and tag counts if they're missing, then crawls.
+async fn crawl(keyspace: Keyspace) -> Result<(), BoxError> {
+    let keyspace = &keyspace;
+    let db = keyspace.open_partition("whynot", kv_sep_partition_option())?;
+    let index = keyspace.open_partition("index", PartitionCreateOptions::default())?;
+    let tags = keyspace.open_partition("tags", PartitionCreateOptions::default())?;
+    let tag_counts = keyspace.open_partition("tag_counts", PartitionCreateOptions::default())?;
+    let authors = keyspace.open_partition("authors", PartitionCreateOptions::default())?;
+
+    // whole archive scans, kept off the workers web serves requests on
+    tokio::task::spawn_blocking({
+        let (keyspace, db, tags, authors, tag_counts) = (
+            keyspace.clone(),
+            db.clone(),
+            tags.clone(),
+            authors.clone(),
+            tag_counts.clone(),
+        );
+        move || backfill(&keyspace, &db, &tags, &authors, &tag_counts)
+    })
+    .await??;
+
+    // a failed step is logged and the rest still run
+    let mut failed = vec![];
+    for i in SECTIONS {
+        if stopped() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        if let Err(e) = fetch_section(keyspace, &db, &index, &tags, &authors, &tag_counts, i).await
+        {
+            FAILURES.inc();
+            warn!("Failed to crawl {i}: {e}");
+            failed.push(i.trim_matches('/'));
+        }
+        SECTION_DURATION
+            .with_label_values(&[i.trim_matches('/')])
+            .set(start.elapsed().as_secs_f64());
+    }
+
+    let misses = keyspace.open_partition("misses", PartitionCreateOptions::default())?;
+    let start = Instant::now();
+    if let Err(e) = fetch_misses(keyspace, &db, &index, &tags, &authors, &tag_counts, &misses).await
+    {
+        FAILURES.inc();
+        warn!("Failed to crawl misses: {e}");
+        failed.push("misses");
+    }
+    SECTION_DURATION
+        .with_label_values(&["misses"])
+        .set(start.elapsed().as_secs_f64());
+
+    if let Some(feed) = &args().podcast_feed
+        && !stopped()
+    {
+        let podcast = keyspace.open_partition("podcast", PartitionCreateOptions::default())?;
+        let start = Instant::now();
+        if let Err(e) = fetch_podcast(keyspace, &podcast, feed).await {
+            FAILURES.inc();
+            warn!("Failed to archive podcast {feed}: {e}");
+            failed.push("podcast");
+        }
+        SECTION_DURATION
+            .with_label_values(&["podcast"])
+            .set(start.elapsed().as_secs_f64());
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("failed to crawl {}", failed.join(", ")).into())
+    }
+}
+
+async fn fetch_section(
+    keyspace: &Keyspace,
+    db: &PartitionHandle,
+    index: &PartitionHandle,
+    tags: &PartitionHandle,
+    authors: &PartitionHandle,
+    tag_counts: &PartitionHandle,
+    section: &str,
+) -> Result<(), BoxError> {
+    let label = section.trim_matches('/');
+    let mut offset = 0;
+    loop {
+        let (count, mut items) = fetch_story_list(offset, section).await?;
+        let new = batch_dl(&mut items, keyspace, db, index, tags, authors, tag_counts).await?;
+        STORIES_FETCHED
+            .with_label_values(&[label])
+            .inc_by(items.len() as u64);
+        STORIES_NEW.with_label_values(&[label]).inc_by(new as u64);
+        offset += items.len();
+        if offset >= count || items.is_empty() || stopped() {
+            return Ok(());
+        }
+        // newest first, so the rest was stored by an earlier crawl
+        if new == 0 && !args().full {
+            info!("No new stories in {section} past {offset}, done");
+            return Ok(());
+        }
+    }
+}
+
+async fn batch_dl(
+    items: &mut [Value],
+    keyspace: &Keyspace,
+    db: &PartitionHandle,
+    index: &PartitionHandle,
+    tags: &PartitionHandle,
+    authors: &PartitionHandle,
+    tag_counts: &PartitionHandle,
+) -> Result<usize, BoxError> {
+    let summaries = summary_partition(keyspace)?;
+    let mut new = 0;
+    let mut batch = keyspace.batch();
+    let mut added: HashMap<String, TagCount> = HashMap::new();
+    let mut seen = HashSet::new();
+    for item in items.iter_mut() {
+        let (Some(website_url), Some(display_date)) = (
+            item["website_url"].as_str(),
+            item["first_publish_date"].as_str(),
+        ) else {
+            warn!("Skipping story without url or date: {}", item["_id"]);
+            continue;
+        };
+        let Ok(ts) = display_date.parse::<Timestamp>() else {
+            warn!("Skipping story with bad date {display_date}: {website_url}");
+            continue;
+        };
+        let website_url = website_url.trim_matches('/').to_owned();
+        let display_date = display_date.to_owned();
+        // stored stories aren't fetched again, nor their images and embeds
+        if db.contains_key(&website_url)? || !seen.insert(website_url.clone()) {
+            continue;
+        }
+
+        let mut imgs = HashSet::new();
+        if let Some(img_url) = item["promo_items"]["basic"]["url"].as_str() {
+            imgs.insert(img_url.to_owned());
+        }
+
+        for credit in item["credits"]["by"].as_array().into_iter().flatten() {
+            if let Some(img_url) = credit["image"]["url"].as_str() {
+                imgs.insert(img_url.to_owned());
+            }
+        }
+
+        let mut videos = vec![];
+        let mut thumbnails = vec![];
+        for c in item["content_elements"].as_array().into_iter().flatten() {
+            match c["type"].as_str() {
+                Some("video") => videos.push(c.clone()),
+                Some("gallery") => {
+                    for i in c["content_elements"].as_array().into_iter().flatten() {
+                        if let Some(img_url) = i["url"].as_str() {
+                            imgs.insert(img_url.to_owned());
+                        }
+                    }
+                }
+                Some("oembed_response") => {
+                    if let Some(url) = c["raw_oembed"]["thumbnail_url"].as_str() {
+                        thumbnails.push(url.to_owned());
+                    }
+                }
+                _ => {}
+            }
+        }
+        videos.extend(promo_video(item).cloned());
+        for video in &videos {
+            if let Some(poster) = video["promo_image"]["url"].as_str() {
+                imgs.insert(poster.to_owned());
+            }
+        }
+
+        let item_str = serde_json::to_string_pretty(item)?;
+        for line in item_str.lines() {
+            if let Some((_, img_name)) = line.split_once(CDN_DOMAIN) {
+                let img_name = img_name.trim().trim_end_matches(',').trim_end_matches('"');
+                let img_url = format!("{CDN_DOMAIN}{img_name}");
+                imgs.insert(img_url);
+            }
+        }
+
+        for url in imgs.iter().chain(&thumbnails) {
+            dl_optional_img(url).await;
+        }
+
+        for video in &videos {
+            dl_video(video).await;
+        }
+
+        if let Some(content_elements) = item["content_elements"].as_array_mut() {
+            for c in content_elements.iter_mut() {
+                if c["type"].as_str() == Some("custom_embed")
+                    && let Some(config) = c["embed"]["config"].as_object()
+                {
+                    let Some(u) = config.get("shorthandScript").or_else(|| config.get("url"))
+                    else {
+                        continue;
+                    };
+                    let Some(url) = u.as_str().filter(|u| !u.is_empty()).map(str::to_owned) else {
+                        warn!("Skipping custom_embed with url {u}");
+                        continue;
+                    };
+                    if args().shorthand
+                        && let Err(e) = archive_shorthand(&url).await
+                    {
+                        FAILURES.inc();
+                        warn!("Failed to archive shorthand {url}: {e}");
+                    }
+                    let (article, img_urls) = extract_article(&url).await?;
+                    for (img_url, img_path) in img_urls {
+                        dl_img(&img_url, &img_path).await;
+                    }
+
+                    if !article.is_empty()
+                        && let Some(c) = c.as_object_mut()
+                    {
+                        c.insert("article".to_owned(), Value::String(article));
+                    }
+                }
+            }
+        }
+
+        let website_url = website_url.as_str();
+        let display_date = display_date.as_str();
+        let v = serde_json::to_string(&item)?;
+        let ts_byte = ts.as_second().to_be_bytes();
+
+        let mut key = Vec::with_capacity(8 + website_url.len());
+        key.extend_from_slice(&ts_byte);
+        key.extend_from_slice(website_url.as_bytes());
+
+        if let Some(sections) = item["taxonomy"]["sections"].as_array() {
+            for section in sections {
+                let Some(path) = section["path"].as_str() else {
+                    warn!("Section without path in {website_url}");
+                    continue;
+                };
+                let path = path.trim_matches('/').to_owned();
+                let key = tag_key(&path, website_url, display_date);
+                batch.insert(tags, key, []);
+
+                let name = section["name"].as_str().unwrap_or_default();
+                let tag = added.entry(path).or_default();
+                tag.name = name.to_owned();
+                tag.count += 1;
+            }
+        }
+
+        for credit in item["credits"]["by"].as_array().into_iter().flatten() {
+            let Some(slug) = author_slug(credit) else {
+                continue;
+            };
+            batch.insert(authors, tag_key(&slug, website_url, display_date), []);
+
+            let name = credit["name"].as_str().unwrap_or(&slug).to_owned();
+            let tag = added.entry(format!("authors/{slug}")).or_default();
+            tag.name = name;
+            tag.count += 1;
+        }
+
+        batch.insert(db, website_url, v);
+        batch.insert(index, key, []);
+        batch.insert(&summaries, website_url, Item::from(&*item).to_bytes());
+        new += 1;
+    }
+
+    for (path, tag) in added {
+        let count = tag_count(tag_counts, &path)?.map_or(0, |t| t.count);
+        let tag = TagCount {
+            count: count + tag.count,
+            ..tag
+        };
+        batch.insert(tag_counts, path, serde_json::to_vec(&tag)?);
+    }
+    batch.commit()?;
+    bump_write_seq(&meta_partition(keyspace)?)?;
+    Ok(new)
+}
+
+/// Writes the summary of every stored story that has none.
+pub fn backfill_summaries(keyspace: &Keyspace, db: &PartitionHandle) -> Result<(), BoxError> {
+    let summaries = summary_partition(keyspace)?;
+    let mut batch = keyspace.batch();
+    let mut count = 0;
+    for i in db.iter() {
+        let (k, v) = i?;
+        if summaries.contains_key(&k)? {
+            continue;
+        }
+        let Ok(json) = serde_json::from_slice::<Value>(&v) else {
+            warn!("invalid json: {}", String::from_utf8_lossy(&k));
+            continue;
+        };
+        batch.insert(&summaries, k, Item::from(&json).to_bytes());
+        count += 1;
+        if count % 1000 == 0 {
+            batch.commit()?;
+            batch = keyspace.batch();
+            info!("Backfilled {count} summaries");
+        }
+    }
+    batch.commit()?;
+    bump_write_seq(&meta_partition(keyspace)?)?;
+    info!("Backfilled {count} summaries");
+    Ok(())
+}
+
+/// Indexes authors once for an archive stored before they were, and recounts
+/// tags and topics if their counts are missing.
+fn backfill(
+    keyspace: &Keyspace,
+    db: &PartitionHandle,
+    tags: &PartitionHandle,
+    authors: &PartitionHandle,
+    tag_counts: &PartitionHandle,
+) -> Result<(), BoxError> {
+    let meta = meta_partition(keyspace)?;
+    // stories may have no credits at all, so `authors` can stay empty
+    let index = !meta.contains_key("authors_indexed")? && authors.is_empty()? && !db.is_empty()?;
+    if index {
+        index_authors(keyspace, db, authors)?;
+        meta.insert("authors_indexed", [])?;
+    }
+    if index || (tag_counts.is_empty()? && !tags.is_empty()?) {
+        rebuild_tag_counts(keyspace, db, tags, authors, tag_counts)?;
+    }
+    Ok(())
+}
+
+/// Fills `authors` from the credits of every stored story.
+fn index_authors(
+    keyspace: &Keyspace,
+    db: &PartitionHandle,
+    authors: &PartitionHandle,
+) -> Result<(), BoxError> {
+    let mut batch = keyspace.batch();
+    for i in db.iter() {
+        let (k, v) = i?;
+        let Ok(json) = serde_json::from_slice::<Value>(&v) else {
+            continue;
+        };
+        let Some(display_date) = json["first_publish_date"]
+            .as_str()
+            .filter(|d| d.parse::<Timestamp>().is_ok())
+        else {
+            continue;
+        };
+        let website_url = String::from_utf8_lossy(&k);
+        for credit in json["credits"]["by"].as_array().into_iter().flatten() {
+            if let Some(slug) = author_slug(credit) {
+                batch.insert(authors, tag_key(&slug, &website_url, display_date), []);
+            }
+        }
+    }
+    batch.commit()?;
+    Ok(())
+}
+
+fn tag_count(tag_counts: &PartitionHandle, path: &str) -> fjall::Result<Option<TagCount>> {
+    let v = tag_counts.get(path)?;
+    Ok(v.and_then(|v| serde_json::from_slice(&v).ok()))
+}
+
+/// Recounts `tag_counts` from the `tags` and `authors` partitions, taking names
+/// from the stories.
+pub fn rebuild_tag_counts(
+    keyspace: &Keyspace,
+    db: &PartitionHandle,
+    tags: &PartitionHandle,
+    authors: &PartitionHandle,
+    tag_counts: &PartitionHandle,
+) -> Result<(), BoxError> {
+    let mut counts: BTreeMap<String, TagCount> = BTreeMap::new();
+    for (partition, prefix) in [(tags, ""), (authors, "authors/")] {
+        for i in partition.keys() {
+            let k = i?;
+            let Some(sep) = k.iter().position(|b| *b == b'|') else {
+                continue;
+            };
+            let key = String::from_utf8_lossy(&k[..sep]);
+            let tag = counts.entry(format!("{prefix}{key}")).or_default();
+            tag.count += 1;
+            if tag.name.is_empty() {
+                let website_url = k.get(sep + 9..).unwrap_or_default();
+                tag.name = tag_name(db, &key, prefix.is_empty(), website_url)?;
+            }
+        }
+    }
+
+    let mut batch = keyspace.batch();
+    for i in tag_counts.keys() {
+        batch.remove(tag_counts, i?);
+    }
+    for (path, tag) in &counts {
+        batch.insert(tag_counts, path, serde_json::to_vec(tag)?);
+    }
+    batch.commit()?;
+    info!("Recounted {} tags", counts.len());
+    Ok(())
+}
+
+/// Display name of section `key`, or of author `key` if not `section`, as found
+/// in story `website_url`.
+fn tag_name(
+    db: &PartitionHandle,
+    key: &str,
+    section: bool,
+    website_url: &[u8],
+) -> fjall::Result<String> {
+    let Some(v) = db.get(website_url)? else {
+        return Ok(String::new());
+    };
+    let Ok(json) = serde_json::from_slice::<Value>(&v) else {
+        return Ok(String::new());
+    };
+    let found = if section {
+        json["taxonomy"]["sections"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|s| s["path"].as_str().map(|p| p.trim_matches('/')) == Some(key))
+    } else {
+        json["credits"]["by"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|c| author_slug(c).as_deref() == Some(key))
+    };
+    Ok(found
+        .and_then(|v| v["name"].as_str())
+        .unwrap_or(key)
+        .to_owned())
+}
+
+/// Local path for an image url, the same one `web` works out from the url.
+fn img_path(url: &str) -> PathBuf {
+    data_dir("imgs").join(get_filename_from_url(url))
+}
+
+/// Archives a podcast feed: the channel and every episode as json in
+/// `podcast`, audio into `media/` and artwork into `imgs/`.
+///
+/// "channel" -> channel without items
+/// "episode" + "|" + ts + id -> item
+#[instrument(skip(keyspace, podcast))]
+async fn fetch_podcast(
+    keyspace: &Keyspace,
+    podcast: &PartitionHandle,
+    feed_url: &str,
+) -> Result<(), BoxError> {
+    let bytes = CLIENT
+        .get(feed_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let mut channel = rss::Channel::read_from(&bytes[..])?;
+    let items = std::mem::take(&mut channel.items);
+
+    let channel_img = channel
+        .itunes_ext()
+        .and_then(|e| e.image())
+        .or_else(|| channel.image().map(|i| i.url()));
+    if let Some(url) = channel_img {
+        dl_optional_img(url).await;
+    }
+
+    let mut batch = keyspace.batch();
+    batch.insert(podcast, "channel", serde_json::to_string(&channel)?);
+    for item in items {
+        let (Some(id), Some(date), Some(enclosure)) =
+            (episode_id(&item), episode_date(&item), item.enclosure())
+        else {
+            warn!(
+                "Skipping episode without id, date or audio: {:?}",
+                item.title()
+            );
+            continue;
+        };
+        let key = tag_key("episode", &id, &date);
+        if podcast.contains_key(&key)? {
+            info!("Episode already exists: {id}");
+            continue;
+        }
+
+        let path = data_dir("media").join(get_filename_from_url(enclosure.url()));
+        if !path.exists() {
+            match dl_capped(enclosure.url(), &path, u64::MAX).await {
+                Ok(_) => info!("Downloaded episode: {}", enclosure.url()),
+                Err(e) => {
+                    FAILURES.inc();
+                    warn!("Failed to download episode {}: {e}", enclosure.url());
+                    continue;
+                }
+            }
+        }
+        if let Some(url) = item.itunes_ext().and_then(|e| e.image()) {
+            dl_optional_img(url).await;
+        }
+
+        batch.insert(podcast, key, serde_json::to_string(&item)?);
+    }
+    batch.commit()?;
+    bump_write_seq(&meta_partition(keyspace)?)?;
+    Ok(())
+}
+
+/// Downloads an image into `imgs/` unless it's there. A failure is counted and
+/// logged, and the story is stored without it.
+async fn dl_optional_img(url: &str) {
+    dl_img(url, &img_path(url)).await;
+}
+
+async fn dl_img(url: &str, path: &Path) {
+    if path.exists() {
+        info!("Image already exists: {}", path.display());
+        return;
+    }
+    match dl_obj(url, path).await {
+        Ok(()) => {
+            IMAGES.inc();
+            info!("Downloaded image: {url}");
+        }
+        Err(e) => warn!("Failed to download image {url}: {e}"),
+    }
+}
+
+/// Fetches the stories readers asked the web server for but it didn't have.
+/// A path is dropped once it's been looked up, the web server records it
+/// again if it's still requested.
+async fn fetch_misses(
+    keyspace: &Keyspace,
+    db: &PartitionHandle,
+    index: &PartitionHandle,
+    tags: &PartitionHandle,
+    authors: &PartitionHandle,
+    tag_counts: &PartitionHandle,
+    misses: &PartitionHandle,
+) -> Result<(), BoxError> {
+    let keys: Vec<_> = misses.keys().collect::<Result<_, _>>()?;
+    for key in keys {
+        if stopped() {
+            break;
+        }
+        let website_url = String::from_utf8_lossy(&key).into_owned();
+        if !db.contains_key(&key)? {
+            match fetch_story(&website_url).await {
+                Ok(Some(item)) => {
+                    STORIES_FETCHED.with_label_values(&["misses"]).inc();
+                    match batch_dl(&mut [item], keyspace, db, index, tags, authors, tag_counts)
+                        .await
+                    {
+                        Ok(new) => STORIES_NEW
+                            .with_label_values(&["misses"])
+                            .inc_by(new as u64),
+                        Err(e) => {
+                            FAILURES.inc();
+                            warn!("Failed to store {website_url}: {e}");
+                            continue;
+                        }
+                    }
+                }
+                Ok(None) => info!("Not a story: {website_url}"),
+                Err(e) => {
+                    FAILURES.inc();
+                    warn!("Failed to fetch {website_url}: {e}");
+                    continue;
+                }
+            }
+        }
+        misses.remove(key)?;
+    }
+    Ok(())
+}
+
+#[instrument]
+async fn fetch_story(website_url: &str) -> Result<Option<Value>, BoxError> {
+    let url = "https://www.wainao.me/pf/api/v3/content/fetch/content-api";
+    let query_json = json!({ "website_url": format!("/{website_url}/") });
+    let query_json = query_json.to_string();
+    let query = encode(&query_json);
+
+    let url = format!("{url}?query={}&_website=wainao", query);
+    let resp = CLIENT.get(url).send().await?;
+    info!("Status: {}", resp.status());
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let json: Value = resp.error_for_status()?.json().await?;
+    Ok(json["website_url"].is_string().then_some(json))
+}
+
+#[instrument]
+async fn fetch_story_list(offset: usize, section: &str) -> Result<(usize, Vec<Value>), BoxError> {
+    let url = "https://www.wainao.me/pf/api/v3/content/fetch/story-feed-sections";
+    let query_json = json!({
+        "feedOffset": offset,
+        "feedSize": 100,
+        "includeSections": section
+    });
+    let query_json = query_json.to_string();
+    let query = encode(&query_json);
+
+    let url = format!("{url}?query={}&d=147&mxId=00000000&_website=wainao", query);
+    let resp = CLIENT.get(url).send().await?;
+    info!("Status: {}", resp.status());
+    let text = resp.text().await?;
+    let json: Value = serde_json::from_str(&text)?;
+    let count = json["count"].as_u64().ok_or("story list without count")? as usize;
+    let items = json["content_elements"]
+        .as_array()
+        .ok_or("story list without content_elements")?;
+    Ok((count, items.to_owned()))
+}
+
+/// Attempts per `dl_obj` download before giving up on server and network errors.
+const DL_ATTEMPTS: u32 = 3;
+
+#[instrument]
+async fn dl_obj(url: &str, path: &Path) -> Result<(), BoxError> {
+    let mut attempt = 1;
+    let bytes = loop {
+        let resp = CLIENT.get(url).send().await;
+        let bytes = match resp.and_then(|r| r.error_for_status()) {
+            Ok(resp) => {
+                info!("Status: {}", resp.status());
+                resp.bytes().await
+            }
+            Err(e) => Err(e),
+        };
+        match bytes {
+            Ok(bytes) => break bytes,
+            // a 4xx won't change by asking again
+            Err(e) if attempt < DL_ATTEMPTS && e.status().is_none_or(|s| s.is_server_error()) => {
+                warn!("Retrying {url} after: {e}");
+                RETRIES.inc();
+                tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                FAILURES.inc();
+                return Err(e.into());
+            }
+        }
+    };
+    BYTES.inc_by(bytes.len() as u64);
+    tokio::fs::write(path, &bytes)
+        .await
+        .inspect_err(|_| FAILURES.inc())?;
+    Ok(())
+}
+
+/// Downloads the best mp4 rendition within `--max-video-size`, and its
+/// subtitles, into `media/`.
+async fn dl_video(video: &Value) {
+    let cap = args().max_video_size * 1_000_000;
+    if cap == 0 {
+        return;
+    }
+
+    for (url, size) in mp4_streams(video) {
+        if size > cap {
+            continue;
+        }
+        let path = data_dir("media").join(get_filename_from_url(url));
+        if path.exists() {
+            info!("Video already exists: {}", path.display());
+            break;
+        }
+        match dl_capped(url, &path, cap).await {
+            Ok(true) => {
+                info!("Downloaded video: {url}");
+                break;
+            }
+            Ok(false) => info!("Video over size cap: {url}"),
+            Err(e) => {
+                FAILURES.inc();
+                warn!("Failed to download video {url}: {e}");
+            }
+        }
+    }
+
+    if let Some(url) = vtt_subtitles(video) {
+        let path = data_dir("media").join(get_filename_from_url(url));
+        if !path.exists()
+            && let Err(e) = dl_obj(url, &path).await
+        {
+            warn!("Failed to download subtitles {url}: {e}");
+        }
+    }
+}
+
+/// Streams `url` to `path`, giving up and returning `false` once it grows
+/// past `cap` bytes.
+#[instrument]
+async fn dl_capped(url: &str, path: &Path, cap: u64) -> Result<bool, BoxError> {
+    let mut resp = CLIENT
+        .get(url)
+        .timeout(std::time::Duration::from_secs(1800))
+        .send()
+        .await?
+        .error_for_status()?;
+    if resp.content_length().is_some_and(|len| len > cap) {
+        return Ok(false);
+    }
+
+    let part = path.with_extension("part");
+    let mut file = tokio::fs::File::create(&part).await?;
+    let mut len = 0;
+    while let Some(chunk) = resp.chunk().await? {
+        len += chunk.len() as u64;
+        if len > cap {
+            drop(file);
+            tokio::fs::remove_file(&part).await?;
+            return Ok(false);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    BYTES.inc_by(len);
+    tokio::fs::rename(part, path).await?;
+    Ok(true)
+}
+
+/// Mirrors a Shorthand story under `shorthand/{dir}/`. Urls below the story
+/// keep their relative paths, everything else is saved under `external/` and
+/// rewritten.
+#[instrument]
+async fn archive_shorthand(web_url: &str) -> Result<(), BoxError> {
+    let dir = data_dir("shorthand").join(shorthand_dir(web_url));
+    let index = dir.join("index.html");
+    if index.exists() {
+        info!("Shorthand already archived: {}", dir.display());
+        return Ok(());
+    }
+
+    let base = Url::parse(web_url)?.join("./")?;
+    let mut html = CLIENT
+        .get(web_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let mut queue = vec![];
+    for r in html_refs(&html) {
+        let Ok(url) = base.join(&r) else {
+            continue;
+        };
+        if let Some(local) = local_asset_path(&base, &url)
+            && !url.as_str().starts_with(base.as_str())
+        {
+            html = html.replace(&r, &local);
+        }
+        queue.push(url);
+    }
+
+    let mut seen = HashSet::new();
+    while let Some(url) = queue.pop() {
+        if !matches!(url.scheme(), "http" | "https") || !seen.insert(url.clone()) {
+            continue;
+        }
+        let Some(local) = local_asset_path(&base, &url) else {
+            warn!("Skipping unsafe asset path: {url}");
+            continue;
+        };
+        let path = dir.join(&local);
+        if path.exists() {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        if local.ends_with(".css") {
+            let resp = CLIENT.get(url.clone()).send().await;
+            let mut css = match resp.and_then(|r| r.error_for_status()) {
+                Ok(r) => {
+                    let css = r.text().await?;
+                    BYTES.inc_by(css.len() as u64);
+                    css
+                }
+                Err(e) => {
+                    FAILURES.inc();
+                    warn!("Failed to download stylesheet {url}: {e}");
+                    continue;
+                }
+            };
+            let up = "../".repeat(local.matches('/').count());
+            for r in css_refs(&css) {
+                let Ok(u) = url.join(&r) else {
+                    continue;
+                };
+                if let Some(l) = local_asset_path(&base, &u)
+                    && !u.as_str().starts_with(base.as_str())
+                {
+                    css = css.replace(&r, &format!("{up}{l}"));
+                }
+                queue.push(u);
+            }
+            tokio::fs::write(&path, css).await?;
+        } else {
+            let is_video = [".mp4", ".webm", ".mov"].iter().any(|e| local.ends_with(e));
+            let cap = if is_video {
+                args().max_video_size * 1_000_000
+            } else {
+                u64::MAX
+            };
+            match dl_capped(url.as_str(), &path, cap).await {
+                Ok(true) => info!("Downloaded asset: {url}"),
+                Ok(false) => info!("Asset over size cap: {url}"),
+                Err(e) => {
+                    FAILURES.inc();
+                    warn!("Failed to download asset {url}: {e}");
+                }
+            }
+        }
+    }
+
+    tokio::fs::write(index, html).await?;
+    Ok(())
+}
+
+/// Path of an asset relative to the story directory, `None` if unsafe.
+fn local_asset_path(base: &Url, url: &Url) -> Option<String> {
+    let no_query = url.as_str().split(['?', '#']).next()?;
+    match no_query.strip_prefix(base.as_str()) {
+        Some(rel) => rel.split('/').all(is_safe_filename).then(|| rel.to_owned()),
+        None => Some(format!("external/{}", get_filename_from_url(url.as_str()))),
+    }
+}
+
+/// Urls of the stylesheets, scripts and media a page loads.
+fn html_refs(html: &str) -> Vec<String> {
+    let document = scraper::Html::parse_document(html);
+    let selector = scraper::Selector::parse(
+        "[src], [srcset], [data-src], [data-srcset], [poster], [style],
+        link[rel~=stylesheet][href], link[rel~=icon][href], link[rel=preload][href]",
+    )
+    .unwrap();
+
+    let mut refs = vec![];
+    for element in document.select(&selector) {
+        let e = element.value();
+        for attr in ["src", "data-src", "poster", "href"] {
+            if attr == "href" && e.name() != "link" {
+                continue;
+            }
+            if let Some(v) = e.attr(attr) {
+                refs.push(v.trim().to_owned());
+            }
+        }
+        for attr in ["srcset", "data-srcset"] {
+            if let Some(v) = e.attr(attr) {
+                refs.extend(
+                    v.split(',')
+                        .filter_map(|s| s.split_whitespace().next())
+                        .map(|s| s.to_owned()),
+                );
+            }
+        }
+        if let Some(style) = e.attr("style") {
+            refs.extend(css_refs(style));
+        }
+    }
+    refs.retain(|r| {
+        !r.is_empty()
+            && !r.starts_with('#')
+            && !["data:", "javascript:", "mailto:"]
+                .iter()
+                .any(|p| r.starts_with(p))
+    });
+    refs
+}
+
+/// Targets of `url(...)` in a stylesheet.
+fn css_refs(css: &str) -> Vec<String> {
+    css.split("url(")
+        .skip(1)
+        .filter_map(|s| s.split_once(')'))
+        .map(|(r, _)| r.trim().trim_matches(['"', '\'']).to_owned())
+        .filter(|r| !r.is_empty() && !r.starts_with("data:") && !r.starts_with('#'))
+        .collect()
+}
+
+#[instrument]
+async fn extract_article(web_url: &str) -> Result<(String, Vec<(String, PathBuf)>), BoxError> {
+    let resp = CLIENT.get(web_url).send().await?;
+    info!("Status: {}", resp.status());
+    let html = resp.text().await?;
+    let document = scraper::Html::parse_document(&html);
+    let selector = scraper::Selector::parse(
+        "h2.Theme-Layer-BodyText-Heading-Large, 
+        div.Theme-Caption.Layout,
+        picture,
+        p",
+    )
+    .unwrap();
+    let source_selector = &scraper::Selector::parse("source").unwrap();
+    let caption_selector = scraper::Selector::parse(".Theme-Caption.Layout").unwrap();
+    let caption_nodes: Vec<_> = document.select(&caption_selector).collect();
+
+    let mut article = String::new();
+    let mut img_urls = Vec::new();
+    for element in document.select(&selector) {
+        if element.value().name() == "picture" {
+            let mut urls = HashSet::new();
+            for source in element.select(source_selector) {
+                if let Some(srcset) = source.value().attr("data-srcset") {
+                    let Some(img_url) = srcset
+                        .split(',')
+                        .map(|s| s.trim())
+                        .next_back()
+                        .and_then(|s| s.split_whitespace().next())
+                    else {
+                        warn!("Empty data-srcset in {web_url}");
+                        continue;
+                    };
+                    urls.insert(img_url);
+                }
+            }
+
+            for url in urls {
+                if url.ends_with("webp") {
+                    continue;
+                }
+                let img_prefix = web_url.trim_end_matches("index.html");
+                let i = url.trim_start_matches("./");
+                let img_url = format!("{img_prefix}{i}");
+                let img_path = img_path(&img_url);
+                let img_name = img_path.file_name().unwrap_or_default().to_string_lossy();
+                article.push_str(&format!("<img src=\"/imgs/{img_name}\" />\n"));
+                img_urls.push((img_url, img_path));
+                break;
+            }
+        } else if element.value().name() == "div" {
+            if let Some(caption) = element
+                .select(&scraper::Selector::parse("div.Theme-Caption.Layout > div").unwrap())
+                .next()
+            {
+                let caption_html = caption.text().collect::<Vec<_>>().join("");
+                article.push_str(&format!("<div class=\"caption\">{}</div>\n", caption_html));
+            }
+        } else if element.value().name() == "p" {
+            let p = element.inner_html();
+            if caption_nodes
+                .iter()
+                .any(|cap| cap.text().any(|_| cap.html().contains(&p)))
+            {
+                continue;
+            }
+            article.push_str(&format!("<p>{}</p>\n", p));
+        } else {
+            let html_fragment = element.html();
+            article.push_str(&html_fragment);
+            article.push('\n');
+        }
+    }
+
+    Ok((article, img_urls))
+}
@@ -1,5 +1,16 @@
-use fjall::{KvSeparationOptions, PartitionCreateOptions};
-use jiff::Timestamp;
+pub mod crawl;
+
+use fjall::{Keyspace, KvSeparationOptions, PartitionCreateOptions, PartitionHandle};
+use jiff::{Timestamp, tz::TimeZone};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    error::Error,
+    fs::{File, OpenOptions, TryLockError, create_dir_all},
+    io::{Read, Write},
+    path::Path,
+};
 
 pub fn kv_sep_partition_option() -> PartitionCreateOptions {
     PartitionCreateOptions::default()
@@ -11,11 +22,163 @@ pub fn kv_sep_partition_option() -> PartitionCreateOptions {
         )
 }
 
-pub fn get_filename_from_url(url: &str) -> &str {
-    url.split('/')
-        .next_back()
-        .and_then(|s| s.split('?').next())
-        .unwrap()
+/// The `meta` partition, holding bookkeeping such as the write sequence number.
+pub fn meta_partition(keyspace: &Keyspace) -> fjall::Result<PartitionHandle> {
+    keyspace.open_partition("meta", PartitionCreateOptions::default())
+}
+
+/// The `summaries` partition: website_url -> `Item`, so listings don't have to
+/// read whole stories.
+pub fn summary_partition(keyspace: &Keyspace) -> fjall::Result<PartitionHandle> {
+    keyspace.open_partition("summaries", PartitionCreateOptions::default())
+}
+
+/// The `runs` partition: be start timestamp -> `crawl::Run` json, one per crawl.
+pub fn run_partition(keyspace: &Keyspace) -> fjall::Result<PartitionHandle> {
+    keyspace.open_partition("runs", PartitionCreateOptions::default())
+}
+
+/// Sequence number of the last spider write, for web to tell its caches are stale.
+pub fn write_seq(meta: &PartitionHandle) -> fjall::Result<u64> {
+    let seq = meta.get("seq")?;
+    Ok(seq
+        .and_then(|v| v.as_ref().try_into().ok())
+        .map_or(0, u64::from_be_bytes))
+}
+
+/// Marks the data as changed, to be called after every spider write.
+pub fn bump_write_seq(meta: &PartitionHandle) -> fjall::Result<()> {
+    let seq = write_seq(meta)?.wrapping_add(1);
+    meta.insert("seq", seq.to_be_bytes())
+}
+
+/// Takes `spider.lock` in the data folder `dir`, creating both if needed, and
+/// writes `holder` into it. fjall is for one process only, so spider and web
+/// each hold it until they exit. Fails naming the current holder if it's taken.
+pub fn lock_data_dir(dir: &Path, holder: &str) -> Result<File, Box<dyn Error + Send + Sync>> {
+    create_dir_all(dir)?;
+    let path = dir.join("spider.lock");
+    let mut lock = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut other = String::new();
+            lock.read_to_string(&mut other)?;
+            let other = match other.trim() {
+                "" => "another spider or web",
+                other => other,
+            };
+            return Err(format!("{} is in use by {other}", dir.display()).into());
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    lock.set_len(0)?;
+    write!(lock, "{holder} (pid {})", std::process::id())?;
+    Ok(lock)
+}
+
+/// Resolves on SIGTERM or ctrl-c, or on ctrl-c only where there are no Unix
+/// signals.
+pub async fn terminated() {
+    #[cfg(unix)]
+    let term = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        signal(SignalKind::terminate()).unwrap().recv().await;
+    };
+    #[cfg(not(unix))]
+    let term = std::future::pending::<()>();
+    tokio::select! {
+        _ = term => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// ANS content element types the web server can render.
+pub const HANDLED_TYPES: &[&str] = &[
+    "text",
+    "image",
+    "header",
+    "interstitial_link",
+    "raw_html",
+    "quote",
+    "custom_embed",
+    "video",
+    "gallery",
+    "list",
+    "table",
+    "divider",
+    "correction",
+    "oembed_response",
+];
+
+pub const CDN_DOMAIN: &str = "https://cloudfront-us-east-1.images.arcpublishing.com/radiofreeasia/";
+
+/// Maps a remote image url to a file name under `imgs/`.
+///
+/// Plain CDN urls keep their file name, which is unique there. Any
+/// other url gets a sanitized stem plus a hash of the full url, so names can
+/// neither escape `imgs/` nor clobber an image from another url, and spider and
+/// web agree on them without keeping a map.
+pub fn get_filename_from_url(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let segment = path.rsplit('/').next().unwrap_or_default();
+    if url.strip_prefix(CDN_DOMAIN) == Some(segment) && is_safe_filename(segment) {
+        return segment.to_owned();
+    }
+
+    let (stem, ext) = match segment.rsplit_once('.') {
+        Some((stem, ext))
+            if !ext.is_empty()
+                && ext.len() <= 5
+                && ext.bytes().all(|b| b.is_ascii_alphanumeric()) =>
+        {
+            (stem, ext.to_ascii_lowercase())
+        }
+        _ => (segment, String::new()),
+    };
+
+    let mut name: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect();
+    name.push_str(&format!("-{:016x}", fnv1a(url.as_bytes())));
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+/// Whether `name` can be used as is for a file directly under `imgs/`.
+pub fn is_safe_filename(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 200
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+}
+
+/// 64-bit FNV-1a, stable across builds unlike `DefaultHasher`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 /// tag_path + "|" + ts + website_url
@@ -32,3 +195,373 @@ pub fn tag_key(tag_path: &str, website_url: &str, display_date: &str) -> Vec<u8>
 
     key
 }
+
+/// Value of the `tag_counts` partition, keyed by tag path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TagCount {
+    pub name: String,
+    pub count: u64,
+}
+
+/// Key of an ANS credit under `authors/`: its slug, id or else name.
+pub fn author_slug(credit: &Value) -> Option<String> {
+    let slug = ["slug", "_id", "name"]
+        .into_iter()
+        .filter_map(|k| credit[k].as_str())
+        .map(str::trim)
+        .find(|s| !s.is_empty())?;
+    Some(slug.replace(['/', '|'], "-"))
+}
+
+/// The promo video of a story, when its lead art or basic promo is one.
+pub fn promo_video(json: &Value) -> Option<&Value> {
+    ["lead_art", "basic"]
+        .into_iter()
+        .map(|k| &json["promo_items"][k])
+        .find(|v| v["type"].as_str() == Some("video"))
+}
+
+/// Mp4 renditions of an ANS video element as (url, file size), best first.
+pub fn mp4_streams(video: &Value) -> Vec<(&str, u64)> {
+    let mut streams: Vec<_> = video["streams"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|s| s["stream_type"].as_str() == Some("mp4"))
+        .filter_map(|s| {
+            let url = s["url"].as_str()?;
+            let bitrate = s["bitrate"].as_u64().unwrap_or_default();
+            let size = s["filesize"].as_u64().unwrap_or_default();
+            Some((url, bitrate, size))
+        })
+        .collect();
+    streams.sort_by_key(|(_, bitrate, _)| Reverse(*bitrate));
+    streams
+        .into_iter()
+        .map(|(url, _, size)| (url, size))
+        .collect()
+}
+
+/// WebVTT subtitles of an ANS video element.
+pub fn vtt_subtitles(video: &Value) -> Option<&str> {
+    video["subtitles"]["urls"]
+        .as_array()?
+        .iter()
+        .find(|u| u["format"].as_str() == Some("WEB_VTT"))?["url"]
+        .as_str()
+}
+
+/// Directory under `shorthand/` holding the full copy of a Shorthand story.
+pub fn shorthand_dir(url: &str) -> String {
+    get_filename_from_url(url.trim_end_matches("index.html").trim_end_matches('/'))
+}
+
+/// Page id of a podcast episode, taken from its guid or else its audio url.
+pub fn episode_id(item: &rss::Item) -> Option<String> {
+    let guid = item
+        .guid()
+        .map(|g| g.value())
+        .or_else(|| item.enclosure().map(|e| e.url()))?;
+    if guid.len() <= 64 && is_safe_filename(guid) {
+        Some(guid.to_owned())
+    } else {
+        Some(format!("{:016x}", fnv1a(guid.as_bytes())))
+    }
+}
+
+/// RFC 3339 publish date of a podcast episode.
+pub fn episode_date(item: &rss::Item) -> Option<String> {
+    let date = jiff::fmt::rfc2822::parse(item.pub_date()?.trim()).ok()?;
+    Some(date.timestamp().to_string())
+}
+
+/// Elements kept by `sanitize_html`, all without attributes but for links.
+const ALLOWED_TAGS: &[&str] = &[
+    "p",
+    "br",
+    "hr",
+    "a",
+    "b",
+    "strong",
+    "i",
+    "em",
+    "u",
+    "s",
+    "sub",
+    "sup",
+    "code",
+    "pre",
+    "blockquote",
+    "ul",
+    "ol",
+    "li",
+    "h3",
+    "h4",
+];
+
+/// Elements dropped with everything in them by `sanitize_html`.
+const DROPPED_TAGS: &[&str] = &[
+    "script", "style", "template", "noscript", "iframe", "object", "embed", "svg", "math", "title",
+    "textarea", "select",
+];
+
+/// Keeps the text, simple formatting and http(s) links of HTML from a feed,
+/// such as podcast show notes, so it can go into a page as is.
+pub fn sanitize_html(html: &str) -> String {
+    let fragment = scraper::Html::parse_fragment(html);
+    let mut out = String::with_capacity(html.len());
+    sanitize_children(fragment.root_element(), &mut out);
+    out
+}
+
+fn sanitize_children(el: scraper::ElementRef, out: &mut String) {
+    for child in el.children() {
+        if let Some(text) = child.value().as_text() {
+            out.push_str(&escape_html(text));
+        } else if let Some(el) = scraper::ElementRef::wrap(child) {
+            let name = el.value().name();
+            if DROPPED_TAGS.contains(&name) {
+                continue;
+            }
+            if !ALLOWED_TAGS.contains(&name) {
+                sanitize_children(el, out);
+                continue;
+            }
+            out.push('<');
+            out.push_str(name);
+            if name == "a"
+                && let Some(href) = el.value().attr("href").filter(|h| {
+                    ["https://", "http://", "mailto:"]
+                        .iter()
+                        .any(|p| h.trim_start().starts_with(p))
+                })
+            {
+                out.push_str(" href=\"");
+                out.push_str(&escape_html(href.trim()));
+                out.push_str("\" rel=\"nofollow noopener\"");
+            }
+            out.push('>');
+            if !matches!(name, "br" | "hr") {
+                sanitize_children(el, out);
+                out.push_str("</");
+                out.push_str(name);
+                out.push('>');
+            }
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Listing row of a story, also stored on its own in the `summaries` partition.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Item {
+    pub headlines: String,
+    pub display_date: String,
+    pub description: String,
+    pub promo_img: Option<String>,
+    pub caption: Option<String>,
+    pub website_url: String,
+    pub section: (String, String),
+}
+
+impl From<&Value> for Item {
+    fn from(json: &Value) -> Self {
+        let headlines = json["headlines"]["basic"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        let display_date = ["publish_date", "first_publish_date", "display_date"]
+            .iter()
+            .find_map(|k| json[k].as_str()?.parse::<Timestamp>().ok())
+            .map(|ts| ts.to_zoned(TimeZone::UTC).strftime("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+
+        let description = json["description"]["basic"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+
+        let promo_img = json
+            .get("promo_items")
+            .and_then(|p| p.get("basic"))
+            .and_then(|b| b.get("url").or_else(|| b["promo_image"].get("url")))
+            .and_then(|img| img.as_str())
+            .map(|s| {
+                let img_name = get_filename_from_url(s);
+                format!("/imgs/{img_name}")
+            });
+
+        let caption = json
+            .get("promo_items")
+            .and_then(|p| p.get("basic"))
+            .and_then(|b| b.get("caption"))
+            .and_then(|c| c.as_str())
+            .map(|s| s.to_owned());
+
+        let mut id = String::new();
+        let mut name = String::new();
+        let mut website_url = String::new();
+        if let Some(obj) = json["websites"].as_object()
+            && let Some((_, value)) = obj.iter().next()
+        {
+            website_url = value["website_url"].as_str().unwrap_or_default().to_owned();
+            let section = &value["website_section"];
+            id = section
+                .get("_id")
+                .unwrap_or_default()
+                .as_str()
+                .unwrap_or_default()
+                .to_owned();
+            name = section
+                .get("name")
+                .unwrap_or_default()
+                .as_str()
+                .unwrap_or_default()
+                .to_owned();
+        }
+        if website_url.is_empty() {
+            website_url = json["website_url"].as_str().unwrap_or_default().to_owned();
+        }
+        Item {
+            headlines,
+            display_date,
+            description,
+            promo_img,
+            caption,
+            website_url,
+            section: (id, name),
+        }
+    }
+}
+
+impl Item {
+    /// Compact encoding for the `summaries` partition.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_safe(name: &str) {
+        assert!(is_safe_filename(name), "unsafe name {name:?}");
+    }
+
+    #[test]
+    fn dot_dot_segments_stay_in_imgs() {
+        for url in [
+            "https://example.com/a/../../etc/passwd",
+            "https://example.com/..",
+            "https://example.com/../.bashrc",
+            "https://example.com/%2e%2e%2fetc.png",
+        ] {
+            let name = get_filename_from_url(url);
+            assert_safe(&name);
+            assert!(!name.contains('/') && !name.contains(".."), "{name}");
+        }
+        assert!(!is_safe_filename(".."));
+        assert!(!is_safe_filename(".hidden"));
+        assert!(!is_safe_filename("a/b.png"));
+        assert!(!is_safe_filename(""));
+    }
+
+    #[test]
+    fn unicode_is_replaced() {
+        let name = get_filename_from_url("https://example.com/图片.PNG");
+        assert_safe(&name);
+        assert!(name.starts_with("__-") && name.ends_with(".png"), "{name}");
+        assert!(!is_safe_filename("图片.png"));
+    }
+
+    #[test]
+    fn query_strings_are_hashed_not_kept() {
+        let a = get_filename_from_url("https://example.com/photo.jpg?w=320");
+        let b = get_filename_from_url("https://example.com/photo.jpg?w=640");
+        let c = get_filename_from_url("https://example.com/photo.jpg#top");
+        assert_ne!(a, b);
+        assert_ne!(a, c);
+        for name in [&a, &b, &c] {
+            assert_safe(name);
+            assert!(
+                name.starts_with("photo-") && name.ends_with(".jpg"),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let stem = "x".repeat(300);
+        let name = get_filename_from_url(&format!("https://example.com/{stem}.webp"));
+        assert_safe(&name);
+        assert_eq!(name.len(), 64 + 17 + 5);
+        assert!(!is_safe_filename(&"x".repeat(201)));
+
+        let ext = get_filename_from_url("https://example.com/a.toolongext");
+        assert!(!ext.ends_with(".toolongext"), "{ext}");
+    }
+
+    #[test]
+    fn cdn_names_pass_through() {
+        assert_eq!(
+            get_filename_from_url(&format!("{CDN_DOMAIN}ABCDEF123.jpg")),
+            "ABCDEF123.jpg"
+        );
+        for url in [
+            format!("{CDN_DOMAIN}ABCDEF123.jpg?auth=1"),
+            format!("{CDN_DOMAIN}sub/ABCDEF123.jpg"),
+            format!("{CDN_DOMAIN}.hidden"),
+            "https://example.com/ABCDEF123.jpg".to_owned(),
+        ] {
+            let name = get_filename_from_url(&url);
+            assert_ne!(name, "ABCDEF123.jpg", "{url}");
+            assert_safe(&name);
+        }
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        // names of stored images must never change between releases
+        assert_eq!(
+            get_filename_from_url("https://example.com/a.png"),
+            "a-fa378f653a3984b6.png"
+        );
+    }
+
+    #[test]
+    fn feed_html_keeps_formatting_and_links() {
+        let html = r#"<p class="x">Show <b>notes</b><br/><a href="https://example.com/?a=1&amp;b=2" onclick="x()">link</a></p><div>text</div>"#;
+        assert_eq!(
+            sanitize_html(html),
+            r#"<p>Show <b>notes</b><br><a href="https://example.com/?a=1&amp;b=2" rel="nofollow noopener">link</a></p>text"#
+        );
+    }
+
+    #[test]
+    fn feed_html_drops_scripts_and_redirects() {
+        let html = r#"<meta http-equiv="refresh" content="0;url=https://evil.example"><script>alert(1)</script><a href="javascript:alert(1)">x</a><img src=x onerror=alert(1)>&lt;b&gt;"#;
+        assert_eq!(sanitize_html(html), "<a>x</a>&lt;b&gt;");
+    }
+}